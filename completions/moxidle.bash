@@ -3,7 +3,7 @@ _moxidle_completions() {
     local cur opts
     COMPREPLY=()
     cur="${COMP_WORDS[COMP_CWORD]}"
-    opts="-c --config -q --quiet -v --verbose"
+    opts="-c --config --log-file --init --force --check --allow-empty -q --quiet -v --verbose lock reset"
     COMPREPLY=( $(compgen -W "${opts}" -- "${cur}") )
     return 0
 }
@@ -1,6 +1,20 @@
 complete -c moxidle -l config -s c -r \
     -d "Specify a custom config path (default: ~/.config/moxidle/config.lua)"
+complete -c moxidle -l log-file -r \
+    -d "Also write logs to this file"
+complete -c moxidle -l init \
+    -d "Write an example config file and exit"
+complete -c moxidle -l force \
+    -d "Overwrite an existing config file with --init"
+complete -c moxidle -l check \
+    -d "Validate the config file and exit"
+complete -c moxidle -l allow-empty \
+    -d "Run without listeners when no config file exists"
 complete -c moxidle -l quiet -s q -x \
     -d "Suppress output (can be repeated)"
 complete -c moxidle -l verbose -s v -x \
     -d "Enable verbose logging (can be repeated)"
+complete -c moxidle -n "__fish_use_subcommand" -f -a lock \
+    -d "Lock the session"
+complete -c moxidle -n "__fish_use_subcommand" -f -a reset \
+    -d "Reset the idle timers"
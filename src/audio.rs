@@ -3,15 +3,19 @@ use calloop::channel;
 use libpulse_binding::{
     self as pulse,
     callbacks::ListResult,
-    context::{FlagSet, subscribe::InterestMaskSet},
+    context::{
+        FlagSet,
+        subscribe::{Facility, InterestMaskSet},
+    },
     error::{Code, PAErr},
-    mainloop::threaded::Mainloop,
+    mainloop::standard::{IterateResult, Mainloop},
     proplist,
 };
 use pulse::context::Context;
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
 #[derive(Debug)]
@@ -21,6 +25,7 @@ struct AudioInhibitor {
     binary: String,
     media_name: Option<String>,
     media_title: Option<String>,
+    role: Option<String>,
 }
 
 impl AudioInhibitor {
@@ -30,6 +35,7 @@ impl AudioInhibitor {
         let pid = proplist.get_str(pulse::proplist::properties::APPLICATION_PROCESS_ID)?;
         let media_name = proplist.get_str(pulse::proplist::properties::MEDIA_NAME);
         let media_title = proplist.get_str(pulse::proplist::properties::MEDIA_TITLE);
+        let role = proplist.get_str(pulse::proplist::properties::MEDIA_ROLE);
 
         Some(Self {
             app_name,
@@ -37,104 +43,285 @@ impl AudioInhibitor {
             binary,
             media_name,
             media_title,
+            role,
         })
     }
+
+    // Streams without a role are matched as "unknown".
+    fn has_role(&self, roles: &[Arc<str>]) -> bool {
+        let role = self.role.as_deref().unwrap_or("unknown");
+        roles.iter().any(|allowed| **allowed == *role)
+    }
 }
 
 impl std::fmt::Display for AudioInhibitor {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "application '{}' (PID: {}, Binary: {}, Media: {}, Title: {})",
+            "application '{}' (PID: {}, Binary: {}, Media: {}, Title: {}, Role: {})",
             self.app_name,
             self.pid,
             self.binary,
             self.media_name.as_deref().unwrap_or("unknown"),
-            self.media_title.as_deref().unwrap_or("unknown")
+            self.media_title.as_deref().unwrap_or("unknown"),
+            self.role.as_deref().unwrap_or("unknown")
         )
     }
 }
 
+// Keyed by sink input or source output index, so that every stream is tracked on its own.
+#[derive(Default)]
+struct AudioInhibitors {
+    playback: HashMap<u32, AudioInhibitor>,
+    capture: HashMap<u32, AudioInhibitor>,
+}
+
+impl AudioInhibitors {
+    fn is_empty(&self) -> bool {
+        self.playback.is_empty() && self.capture.is_empty()
+    }
+}
+
+// Replaces the tracked streams with the uncorked ones of a full enumeration, so that streams
+// removed in the meantime can't linger.
+fn replace_inhibitors(
+    inhibitors: &mut HashMap<u32, AudioInhibitor>,
+    current: HashMap<u32, AudioInhibitor>,
+    kind: &str,
+) {
+    current
+        .iter()
+        .filter(|(index, _)| !inhibitors.contains_key(index))
+        .for_each(|(_, inhibitor)| log::info!("Added audio {kind} inhibitor for {inhibitor}"));
+    inhibitors
+        .iter()
+        .filter(|(index, _)| !current.contains_key(index))
+        .for_each(|(_, inhibitor)| log::info!("Removed audio {kind} inhibitor for {inhibitor}"));
+
+    *inhibitors = current;
+}
+
+// Only streams with one of `roles` inhibit, every stream does if it's None.
+fn insert_stream(
+    streams: &mut HashMap<u32, AudioInhibitor>,
+    index: u32,
+    corked: bool,
+    proplist: &proplist::Proplist,
+    roles: Option<&[Arc<str>]>,
+) {
+    if !corked
+        && let Some(inhibitor) = AudioInhibitor::new(proplist)
+        && roles.is_none_or(|roles| inhibitor.has_role(roles))
+    {
+        streams.insert(index, inhibitor);
+    }
+}
+
+fn send_audio_inhibit(inhibitors: &Mutex<AudioInhibitors>, event_sender: &channel::Sender<Event>) {
+    if let Err(e) = event_sender.send(Event::AudioInhibit(!inhibitors.lock().unwrap().is_empty())) {
+        log::error!("Failed to send AudioInhibit event: {e}");
+    }
+}
+
 fn process_sink_inputs(
-    inhibitors: Arc<Mutex<HashMap<String, AudioInhibitor>>>,
+    inhibitors: Arc<Mutex<AudioInhibitors>>,
     introspector: &pulse::context::introspect::Introspector,
     event_sender: &channel::Sender<Event>,
+    roles: Option<Arc<[Arc<str>]>>,
 ) {
     introspector.get_sink_input_info_list({
         let event_sender = event_sender.clone();
+        let mut streams = HashMap::new();
         move |result| match result {
             ListResult::Error => {
                 log::error!("Error retrieving sink input info list")
             }
-            ListResult::Item(info) => {
-                let mut inhibitors = inhibitors.lock().unwrap();
+            ListResult::Item(info) => insert_stream(
+                &mut streams,
+                info.index,
+                info.corked,
+                &info.proplist,
+                roles.as_deref(),
+            ),
+            ListResult::End => {
+                replace_inhibitors(
+                    &mut inhibitors.lock().unwrap().playback,
+                    std::mem::take(&mut streams),
+                    "playback",
+                );
+                send_audio_inhibit(&inhibitors, &event_sender);
+            }
+        }
+    });
+}
 
-                if !info.corked {
-                    if let Some(inhibitor) = AudioInhibitor::new(&info.proplist) {
-                        log::info!("Added audio inhibitor for {inhibitor}");
-                        inhibitors.insert(inhibitor.binary.clone(), inhibitor);
-                    }
-                } else if let Some(name) = info
-                    .proplist
-                    .get_str(pulse::proplist::properties::APPLICATION_PROCESS_BINARY)
-                    && let Some(removed) = inhibitors.remove(&name)
-                {
-                    log::info!("Removed audio inhibitor for {removed}");
-                }
+fn process_source_outputs(
+    inhibitors: Arc<Mutex<AudioInhibitors>>,
+    introspector: &pulse::context::introspect::Introspector,
+    event_sender: &channel::Sender<Event>,
+    roles: Option<Arc<[Arc<str>]>>,
+) {
+    introspector.get_source_output_info_list({
+        let event_sender = event_sender.clone();
+        let mut streams = HashMap::new();
+        move |result| match result {
+            ListResult::Error => {
+                log::error!("Error retrieving source output info list")
             }
+            ListResult::Item(info) => insert_stream(
+                &mut streams,
+                info.index,
+                info.corked,
+                &info.proplist,
+                roles.as_deref(),
+            ),
             ListResult::End => {
-                if let Err(e) =
-                    event_sender.send(Event::AudioInhibit(!inhibitors.lock().unwrap().is_empty()))
-                {
-                    log::error!("Failed to send AudioInhibit event: {e}");
-                }
+                replace_inhibitors(
+                    &mut inhibitors.lock().unwrap().capture,
+                    std::mem::take(&mut streams),
+                    "capture",
+                );
+                send_audio_inhibit(&inhibitors, &event_sender);
             }
         }
     });
 }
 
-pub async fn serve(
-    event_sender: channel::Sender<Event>,
-    ignore_audio_inhibit: bool,
-) -> Result<(), pulse::error::PAErr> {
-    if ignore_audio_inhibit {
-        return Ok(());
-    }
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
 
-    let inhibitors = Arc::new(Mutex::new(HashMap::new()));
+// Connects to the server and listens until the connection goes away. Returns Ok(()) if the
+// connection was established before being lost and Err if it never became ready.
+fn run(
+    event_sender: &channel::Sender<Event>,
+    ignore_audio_capture: bool,
+    roles: Option<Arc<[Arc<str>]>>,
+) -> Result<(), PAErr> {
+    let inhibitors = Arc::new(Mutex::new(AudioInhibitors::default()));
 
     let mut mainloop = Mainloop::new().ok_or(PAErr(Code::NoData as i32))?;
     let mut context =
         Context::new(&mainloop, "playback-listener").ok_or(PAErr(Code::NoData as i32))?;
     context.connect(None, FlagSet::NOFLAGS, None)?;
-    mainloop.start()?;
 
-    loop {
+    let mut connected = false;
+    let result = loop {
+        match mainloop.iterate(true) {
+            IterateResult::Success(_) => {}
+            IterateResult::Quit(_) => break Err(PAErr(Code::Killed as i32)),
+            IterateResult::Err(e) => break Err(e),
+        }
+
         match context.get_state() {
-            pulse::context::State::Ready => break,
-            pulse::context::State::Failed => return Err(PAErr(Code::ConnectionRefused as i32)),
+            pulse::context::State::Ready if !connected => {
+                connected = true;
+                log::info!("Connected to audio server");
+
+                let introspector = context.introspect();
+                process_sink_inputs(
+                    Arc::clone(&inhibitors),
+                    &introspector,
+                    event_sender,
+                    roles.clone(),
+                );
+                if !ignore_audio_capture {
+                    process_source_outputs(
+                        Arc::clone(&inhibitors),
+                        &introspector,
+                        event_sender,
+                        roles.clone(),
+                    );
+                }
+
+                context.set_subscribe_callback(Some(Box::new({
+                    let inhibitors = Arc::clone(&inhibitors);
+                    let event_sender = event_sender.clone();
+                    let roles = roles.clone();
+                    move |facility, _, _| match facility {
+                        Some(Facility::SinkInput) => process_sink_inputs(
+                            Arc::clone(&inhibitors),
+                            &introspector,
+                            &event_sender,
+                            roles.clone(),
+                        ),
+                        Some(Facility::SourceOutput) => process_source_outputs(
+                            Arc::clone(&inhibitors),
+                            &introspector,
+                            &event_sender,
+                            roles.clone(),
+                        ),
+                        _ => {}
+                    }
+                })));
+
+                let mut interest = InterestMaskSet::SINK_INPUT;
+                if !ignore_audio_capture {
+                    interest |= InterestMaskSet::SOURCE_OUTPUT;
+                }
+                context.subscribe(interest, |_| {});
+            }
+            pulse::context::State::Failed => break Err(PAErr(Code::ConnectionRefused as i32)),
             pulse::context::State::Terminated => {
-                return Err(PAErr(Code::ConnectionTerminated as i32));
+                break Err(PAErr(Code::ConnectionTerminated as i32));
             }
-            _ => mainloop.wait(),
+            _ => {}
         }
+    };
+
+    // The subscribe callback holds an introspector referencing the context,
+    // drop it so the context and mainloop are freed.
+    context.set_subscribe_callback(None);
+    context.disconnect();
+
+    if !connected {
+        return result;
+    }
+
+    if let Err(e) = result {
+        log::warn!("Lost connection to audio server: {e}");
+    }
+
+    if let Err(e) = event_sender.send(Event::AudioInhibit(false)) {
+        log::error!("Failed to send AudioInhibit event: {e}");
     }
 
-    let introspector = context.introspect();
+    Ok(())
+}
 
-    process_sink_inputs(Arc::clone(&inhibitors), &introspector, &event_sender);
-    context.set_subscribe_callback(Some(Box::new({
-        let inhibitors = Arc::clone(&inhibitors);
-        move |_, _, _| {
-            process_sink_inputs(Arc::clone(&inhibitors), &introspector, &event_sender);
+pub async fn serve(
+    event_sender: channel::Sender<Event>,
+    ignore_audio_inhibit: bool,
+    ignore_audio_capture: bool,
+    roles: Option<Arc<[Arc<str>]>>,
+) -> anyhow::Result<()> {
+    if ignore_audio_inhibit {
+        return Ok(());
+    }
+
+    let thread_sender = event_sender.clone();
+    let result: Result<(), _> = tokio::task::spawn_blocking(move || {
+        let mut delay = INITIAL_RECONNECT_DELAY;
+        loop {
+            match run(&thread_sender, ignore_audio_capture, roles.clone()) {
+                Ok(()) => delay = INITIAL_RECONNECT_DELAY,
+                Err(e) => log::warn!("Failed to connect to audio server: {e}"),
+            }
+
+            log::info!("Reconnecting to audio server in {}s", delay.as_secs());
+            std::thread::sleep(delay);
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
         }
-    })));
-    context.subscribe(InterestMaskSet::SINK_INPUT, |_| {});
+    })
+    .await;
 
-    // PulseAudio's event loop (mainloop) and context must remain alive
-    // for the duration of the subscription.
-    Box::leak(Box::new(context));
-    Box::leak(Box::new(mainloop));
+    // The thread only ever stops by panicking, the inhibitors it tracked are gone with it.
+    if let Err(e) = event_sender.send(Event::AudioInhibit(false)) {
+        log::error!("Failed to send AudioInhibit event: {e}");
+    }
 
-    Ok(())
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) if e.is_panic() => Err(anyhow::anyhow!("audio thread panicked")),
+        Err(e) => Err(e.into()),
+    }
 }
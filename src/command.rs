@@ -0,0 +1,370 @@
+use crate::{
+    config::{Command, CommandOverflow, ListenerConfig, MoxidleConfig, Shell},
+    upower::{Power, PowerSource},
+};
+use std::{
+    cell::Cell,
+    io,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Child,
+    runtime::Handle,
+    sync::Semaphore,
+    task::JoinHandle,
+};
+
+const MAX_COMMAND_OUTPUT: usize = 8 * 1024;
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+// How long output is still read after the command exited, forked children can keep the pipes open.
+const OUTPUT_GRACE_PERIOD: Duration = Duration::from_millis(100);
+
+// Describes what triggered a command, exported to it through MOXIDLE_* environment variables
+// and substituted for the placeholders in its command line.
+pub struct CommandContext<'a> {
+    event: &'static str,
+    listener_timeout: Option<u32>,
+    // Overrides the runner's working directory.
+    cwd: Option<&'a Path>,
+    power: &'a Power,
+}
+
+impl<'a> CommandContext<'a> {
+    pub fn new(event: &'static str, power: &'a Power) -> Self {
+        Self {
+            event,
+            listener_timeout: None,
+            cwd: None,
+            power,
+        }
+    }
+
+    // For commands of a listener, `timeout` is the one it was armed with.
+    pub fn listener(mut self, config: &'a ListenerConfig, timeout: u32) -> Self {
+        self.listener_timeout = Some(timeout);
+        self.cwd = config.cwd.as_deref();
+        self
+    }
+
+    fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("MOXIDLE_EVENT", self.event.to_string()),
+            (
+                "MOXIDLE_BATTERY_PERCENT",
+                format!("{:.0}", self.power.percentage()),
+            ),
+            ("MOXIDLE_BATTERY_STATE", self.power.state().to_string()),
+            (
+                "MOXIDLE_ON_BATTERY",
+                (*self.power.source() == PowerSource::Battery).to_string(),
+            ),
+        ];
+
+        if let Some(timeout) = self.listener_timeout {
+            env.push(("MOXIDLE_LISTENER_TIMEOUT", (timeout / 1000).to_string()));
+            env.push(("MOXIDLE_LISTENER_TIMEOUT_MS", timeout.to_string()));
+        }
+
+        env
+    }
+
+    fn placeholder(&self, name: &str) -> Option<String> {
+        match name {
+            "battery" => Some(format!("{:.0}", self.power.percentage())),
+            "state" => Some(self.power.state().to_string()),
+            "event" => Some(self.event.to_string()),
+            "timeout" => Some(
+                self.listener_timeout
+                    .map(|timeout| (timeout / 1000).to_string())
+                    .unwrap_or_default(),
+            ),
+            _ => None,
+        }
+    }
+
+    // Replaces {battery}, {state}, {event} and {timeout}, with {{ and }} for literal braces.
+    // Anything else is kept as written so that shell syntax like ${VAR} keeps working, and
+    // commands without placeholders are run as written, braces included.
+    fn substitute(&self, template: &str) -> String {
+        let mut result = String::with_capacity(template.len());
+        let mut substituted = false;
+        let mut rest = template;
+        while let Some(start) = rest.find(['{', '}']) {
+            result.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                result.push_str(&rest[..1]);
+                rest = &rest[2..];
+                continue;
+            }
+
+            let value = rest
+                .strip_prefix('{')
+                .and_then(|inner| inner.split_once('}'))
+                .and_then(|(name, after)| Some((self.placeholder(name)?, after)));
+            match value {
+                Some((value, after)) => {
+                    result.push_str(&value);
+                    substituted = true;
+                    rest = after;
+                }
+                None => {
+                    result.push_str(&rest[..1]);
+                    rest = &rest[1..];
+                }
+            }
+        }
+
+        if !substituted {
+            return template.to_string();
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+pub struct CommandRunner {
+    runtime: Handle,
+    shell: Shell,
+    limit: Arc<Semaphore>,
+    overflow: CommandOverflow,
+    timeout: Option<Duration>,
+    cwd: Option<PathBuf>,
+    // Commands that weren't dropped, for metrics.
+    executed: Cell<u64>,
+}
+
+impl CommandRunner {
+    pub fn new(runtime: Handle, config: &MoxidleConfig) -> Self {
+        Self {
+            runtime,
+            shell: config.shell.clone(),
+            limit: Arc::new(Semaphore::new(config.max_concurrent_commands())),
+            overflow: config.command_overflow,
+            timeout: config.command_timeout(),
+            cwd: config.cwd.clone(),
+            executed: Cell::new(0),
+        }
+    }
+
+    // Commands that are already running keep counting against the previous limit.
+    pub fn reconfigure(&mut self, config: &MoxidleConfig) {
+        let executed = self.executed.get();
+        *self = Self::new(self.runtime.clone(), config);
+        self.executed.set(executed);
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn executed(&self) -> u64 {
+        self.executed.get()
+    }
+
+    // Runs the command in the background, returns None if it was dropped without running.
+    pub fn run(&self, command: Command, context: &CommandContext) -> Option<JoinHandle<()>> {
+        let mut process = match &command {
+            Command::Shell(command_line) => {
+                let Some((program, args)) = self.shell.argv() else {
+                    log::error!("failed to execute command '{command}': shell is empty");
+                    return None;
+                };
+
+                let mut process = tokio::process::Command::new(program);
+                process.args(args).arg(context.substitute(command_line));
+                process
+            }
+            // Argv commands are executed directly, without a shell.
+            Command::Argv(argv) => {
+                let Some((program, args)) = argv.split_first() else {
+                    log::error!("failed to execute command: argv is empty");
+                    return None;
+                };
+
+                let mut process = tokio::process::Command::new(program.as_ref());
+                process.args(args.iter().map(|arg| context.substitute(arg)));
+                process
+            }
+        };
+
+        // Output is only logged at debug level, don't capture it otherwise.
+        let capture = log::log_enabled!(log::Level::Debug);
+        let output = || {
+            if capture {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            }
+        };
+        process
+            .envs(context.env())
+            .stdout(output())
+            .stderr(output());
+        if let Some(cwd) = context.cwd.or(self.cwd.as_deref()) {
+            process.current_dir(cwd);
+        }
+
+        let permit = match self.overflow {
+            CommandOverflow::Drop => match Arc::clone(&self.limit).try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    log::warn!("Too many commands running, dropping '{command}'");
+                    return None;
+                }
+            },
+            CommandOverflow::Queue => {
+                if self.limit.available_permits() == 0 {
+                    log::warn!("Too many commands running, queueing '{command}'");
+                }
+                None
+            }
+        };
+
+        self.executed.set(self.executed.get() + 1);
+        let limit = Arc::clone(&self.limit);
+        let timeout = self.timeout;
+        let handle = self.runtime.spawn(async move {
+            let permit = match permit {
+                Some(permit) => permit,
+                None => match limit.acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => return,
+                },
+            };
+
+            let mut child = match process.spawn() {
+                Ok(child) => child,
+                Err(err) => {
+                    log::error!("failed to execute command '{command}': {err}");
+                    return;
+                }
+            };
+
+            let stdout = child.stdout.take();
+            let stderr = child.stderr.take();
+            let status = async {
+                let Some(timeout) = timeout else {
+                    return child.wait().await;
+                };
+
+                match tokio::time::timeout(timeout, child.wait()).await {
+                    Ok(status) => status,
+                    Err(_) => {
+                        log::warn!(
+                            "command '{command}' didn't finish within {}s, terminating it",
+                            timeout.as_secs()
+                        );
+                        terminate(&mut child).await
+                    }
+                }
+            };
+
+            let mut stdout_output = Vec::new();
+            let mut stderr_output = Vec::new();
+            let status = {
+                let output = async {
+                    tokio::join!(
+                        read_output(stdout, &mut stdout_output),
+                        read_output(stderr, &mut stderr_output)
+                    )
+                };
+                tokio::pin!(status, output);
+                tokio::select! {
+                    status = &mut status => {
+                        // Only the command itself counts against the limit, not children
+                        // it left behind holding the pipes open.
+                        drop(permit);
+                        _ = tokio::time::timeout(OUTPUT_GRACE_PERIOD, output).await;
+                        status
+                    }
+                    _ = &mut output => status.await,
+                }
+            };
+
+            log_command_output(&command, "stdout", &stdout_output);
+            log_command_output(&command, "stderr", &stderr_output);
+            match status {
+                Ok(status) if !status.success() => {
+                    log::error!("command '{command}' failed with exit status {status}")
+                }
+                Ok(_) => {}
+                Err(err) => log::error!("failed to wait on command '{command}': {err}"),
+            }
+        });
+
+        Some(handle)
+    }
+}
+
+// Sends SIGTERM and falls back to SIGKILL if the command is still running after the grace period.
+async fn terminate(child: &mut Child) -> io::Result<ExitStatus> {
+    if let Some(pid) = child.id() {
+        // SAFETY: kill has no memory safety requirements, the pid belongs to a child that
+        // hasn't been reaped yet so it can't have been reused.
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+    }
+
+    match tokio::time::timeout(KILL_GRACE_PERIOD, child.wait()).await {
+        Ok(status) => status,
+        Err(_) => {
+            child.kill().await?;
+            child.wait().await
+        }
+    }
+}
+
+// Keeps one byte past the limit to tell truncated output apart and discards the rest.
+async fn read_output(stream: Option<impl AsyncRead + Unpin>, output: &mut Vec<u8>) {
+    let Some(mut stream) = stream else {
+        return;
+    };
+
+    _ = (&mut stream)
+        .take(MAX_COMMAND_OUTPUT as u64 + 1)
+        .read_to_end(output)
+        .await;
+    _ = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await;
+}
+
+fn log_command_output(command: &Command, stream: &str, output: &[u8]) {
+    if output.is_empty() {
+        return;
+    }
+
+    let suffix = if output.len() > MAX_COMMAND_OUTPUT {
+        "\n[output truncated]"
+    } else {
+        ""
+    };
+    let output = String::from_utf8_lossy(&output[..output.len().min(MAX_COMMAND_OUTPUT)]);
+    log::debug!(
+        "command '{command}' {stream}:\n{}{suffix}",
+        output.trim_end()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn braces_are_escaped_only_with_placeholders() {
+        let power = Power::default();
+        let context = CommandContext::new("timeout", &power);
+
+        assert_eq!(
+            context.substitute("notify-send '{event}: {{x}}' ${HOME}"),
+            "notify-send 'timeout: {x}' ${HOME}"
+        );
+        // Commands written before placeholders existed keep their braces.
+        assert_eq!(
+            context.substitute("awk '{{print}}' {unknown}"),
+            "awk '{{print}}' {unknown}"
+        );
+    }
+}
@@ -1,7 +1,139 @@
-use crate::upower::{BatteryLevel, BatteryState};
-use mlua::{Lua, LuaSerdeExt};
+use crate::{
+    upower::{BatteryLevel, BatteryState},
+    usb::{self, UsbMatcher},
+};
+#[cfg(feature = "lua")]
+use mlua::{Lua, LuaSerdeExt, Table, Value};
 use serde::{Deserialize, Deserializer};
-use std::{fs, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fmt::{self, Display},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+const DEFAULT_CONFIG: &str = r#"return {
+  general = {
+    -- Command executed when the session is locked, e.g. by 'loginctl lock-session'
+    lock_cmd = "pidof hyprlock || hyprlock",
+
+    -- Command executed when the session is unlocked, e.g. by 'loginctl unlock-session'
+    unlock_cmd = "pkill -USR1 hyprlock",
+
+    before_sleep_cmd = "loginctl lock-session", -- Command executed before sleep
+    after_sleep_cmd = "notify-send 'Awake!'", -- Command executed after waking up
+    ignore_dbus_inhibit = false, -- Ignore DBus idle-inhibit requests
+    ignore_systemd_inhibit = false, -- Ignore systemd idle inhibitors
+  },
+  listeners = {
+    {
+      conditions = { "on_ac" }, -- Conditions needed to be fulfilled for the timeout to launch
+      timeout = 300, -- Idle timeout in seconds
+      on_timeout = "loginctl lock-session", -- Command executed on timeout
+      on_resume = "notify-send 'Welcome back!'", -- Command executed on user activity
+    },
+    {
+      conditions = { "on_battery" },
+      timeout = 180,
+      on_timeout = "loginctl lock-session",
+    },
+    {
+      conditions = { "on_battery", { battery_below = 20 } },
+      timeout = 300,
+      on_timeout = "systemctl suspend",
+    },
+  },
+}
+"#;
+
+const DEFAULT_CONFIG_TOML: &str = r#"[general]
+# Command executed when the session is locked, e.g. by 'loginctl lock-session'
+lock_cmd = "pidof hyprlock || hyprlock"
+
+# Command executed when the session is unlocked, e.g. by 'loginctl unlock-session'
+unlock_cmd = "pkill -USR1 hyprlock"
+
+before_sleep_cmd = "loginctl lock-session" # Command executed before sleep
+after_sleep_cmd = "notify-send 'Awake!'" # Command executed after waking up
+ignore_dbus_inhibit = false # Ignore DBus idle-inhibit requests
+ignore_systemd_inhibit = false # Ignore systemd idle inhibitors
+
+[[listeners]]
+conditions = ["on_ac"] # Conditions needed to be fulfilled for the timeout to launch
+timeout = 300 # Idle timeout in seconds
+on_timeout = "loginctl lock-session" # Command executed on timeout
+on_resume = "notify-send 'Welcome back!'" # Command executed on user activity
+
+[[listeners]]
+conditions = ["on_battery"]
+timeout = 180
+on_timeout = "loginctl lock-session"
+
+[[listeners]]
+conditions = ["on_battery", { battery_below = 20 }]
+timeout = 300
+on_timeout = "systemctl suspend"
+"#;
+
+// Config file formats, picked by file extension.
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Lua,
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    const EXTENSIONS: [&str; 5] = ["lua", "toml", "yaml", "yml", "json"];
+
+    fn from_path(path: &Path) -> anyhow::Result<Self> {
+        match path.extension().and_then(OsStr::to_str) {
+            Some("lua") if cfg!(feature = "lua") => Ok(Self::Lua),
+            Some("lua") => Err(anyhow::anyhow!(
+                "{}: moxidle was built without Lua support",
+                path.display()
+            )),
+            Some("toml") => Ok(Self::Toml),
+            Some("yaml" | "yml") => Ok(Self::Yaml),
+            Some("json") => Ok(Self::Json),
+            _ => Err(anyhow::anyhow!(
+                "{}: unsupported config format, expected a .lua, .toml, .yaml or .json file",
+                path.display()
+            )),
+        }
+    }
+
+    // Parses a declarative config file. Lua files are evaluated instead.
+    fn parse(self, contents: &str) -> anyhow::Result<serde_json::Value> {
+        let value = match self {
+            Self::Lua => unreachable!("Lua configs are evaluated, not parsed"),
+            Self::Toml => toml::from_str(contents)?,
+            Self::Yaml => serde_norway::from_str(contents)?,
+            Self::Json => serde_json::from_str(contents)?,
+        };
+
+        Ok(value)
+    }
+
+    // The example config written by --init. YAML and JSON are derived from the TOML one,
+    // losing its comments.
+    fn example(self) -> anyhow::Result<String> {
+        let example = match self {
+            Self::Lua => DEFAULT_CONFIG.to_string(),
+            Self::Toml => DEFAULT_CONFIG_TOML.to_string(),
+            Self::Yaml => serde_norway::to_string(&Self::Toml.parse(DEFAULT_CONFIG_TOML)?)?,
+            Self::Json => {
+                serde_json::to_string_pretty(&Self::Toml.parse(DEFAULT_CONFIG_TOML)?)? + "\n"
+            }
+        };
+
+        Ok(example)
+    }
+}
 
 #[derive(Deserialize)]
 pub struct Config {
@@ -10,57 +142,560 @@ pub struct Config {
 }
 
 impl Config {
-    pub fn load(path: Option<PathBuf>) -> anyhow::Result<(MoxidleConfig, Vec<ListenerConfig>)> {
+    // With `allow_empty`, a missing default config yields the default settings and no listeners.
+    pub fn load(
+        path: Option<PathBuf>,
+        allow_empty: bool,
+    ) -> anyhow::Result<(MoxidleConfig, Vec<ListenerConfig>)> {
+        let config_path = match path.or_else(Self::env_path) {
+            Some(path) => path,
+            None => match Self::find()? {
+                Some(path) => path,
+                None if allow_empty => return Ok((MoxidleConfig::default(), Vec::new())),
+                None => return Err(Self::not_found()?),
+            },
+        };
+        let config = Self::parse(&config_path)?;
+
+        config
+            .listeners
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, listener)| {
+                if listener.timeout.is_none() && listener.timeout_ms.is_none() {
+                    return Err(anyhow::anyhow!(
+                        "listener {} is missing a timeout, set either `timeout` or `timeout_ms`",
+                        i + 1
+                    ));
+                }
+
+                if listener.timeout_millis() == 0 {
+                    return Err(anyhow::anyhow!("listener {} has a zero timeout", i + 1));
+                }
+
+                if listener.warn_before_secs.is_some() && listener.warn_timeout_millis().is_none() {
+                    return Err(anyhow::anyhow!(
+                        "listener {} has a `warn_before_secs` that isn't shorter than its timeout",
+                        i + 1
+                    ));
+                }
+
+                if listener.ignore_inhibitors && listener.respects_inhibitors == Some(true) {
+                    return Err(anyhow::anyhow!(
+                        "listener {} sets both `ignore_inhibitors` and `respects_inhibitors`",
+                        i + 1
+                    ));
+                }
+
+                if listener.lock && listener.sets_lock_state == Some(false) {
+                    return Err(anyhow::anyhow!(
+                        "listener {} sets `lock` but not `sets_lock_state`",
+                        i + 1
+                    ));
+                }
+
+                if !listener.lock && (listener.lock_cmd.is_some() || listener.unlock_cmd.is_some())
+                {
+                    return Err(anyhow::anyhow!(
+                        "listener {} has a `lock_cmd` or `unlock_cmd` but doesn't set `lock`",
+                        i + 1
+                    ));
+                }
+
+                if let Some(name) = listener.name.as_ref()
+                    && config.listeners[..i]
+                        .iter()
+                        .any(|other| other.name.as_ref() == Some(name))
+                {
+                    return Err(anyhow::anyhow!(
+                        "listener {} reuses the name '{name}'",
+                        i + 1
+                    ));
+                }
+
+                listener.conditions.iter().try_for_each(|condition| {
+                    condition
+                        .validate()
+                        .map_err(|e| anyhow::anyhow!("listener {} has {e}", i + 1))?;
+
+                    let Some(name) = condition.dependency() else {
+                        return Ok(());
+                    };
+
+                    // A listener can only fire while armed, so depending on itself never arms it.
+                    if listener.name.as_ref() == Some(name) {
+                        return Err(anyhow::anyhow!("listener {} depends on itself", i + 1));
+                    }
+
+                    if !config
+                        .listeners
+                        .iter()
+                        .any(|other| other.name.as_ref() == Some(name))
+                    {
+                        return Err(anyhow::anyhow!(
+                            "listener {} depends on unknown listener '{name}'",
+                            i + 1
+                        ));
+                    }
+
+                    Ok(())
+                })
+            })?;
+
+        // A missing directory only fails the commands using it, it may be mounted later.
+        let warn_missing_cwd = |cwd: Option<&PathBuf>, owner: &str| {
+            if let Some(cwd) = cwd
+                && !cwd.is_dir()
+            {
+                log::warn!(
+                    "{owner} has a `cwd` that isn't a directory: {}",
+                    cwd.display()
+                );
+            }
+        };
+        warn_missing_cwd(config.general.cwd.as_ref(), "general");
+        config
+            .listeners
+            .iter()
+            .enumerate()
+            .for_each(|(i, listener)| {
+                warn_missing_cwd(listener.cwd.as_ref(), &format!("listener {}", i + 1))
+            });
+
+        if config.general.max_concurrent_commands == Some(0) {
+            return Err(anyhow::anyhow!(
+                "max_concurrent_commands has to be at least 1"
+            ));
+        }
+
+        Ok((config.general, config.listeners))
+    }
+
+    #[cfg(feature = "lua")]
+    fn parse(path: &Path) -> anyhow::Result<Self> {
+        let lua = Lua::new();
+        let general = lua.create_table().map_err(|e| anyhow::anyhow!("{e}"))?;
+        let listeners = lua.create_table().map_err(|e| anyhow::anyhow!("{e}"))?;
+        Self::load_file(path, &mut Vec::new(), &mut |format, contents| {
+            let table: Table = match format {
+                Format::Lua => lua
+                    .load(contents)
+                    .eval()
+                    .map_err(|e| anyhow::anyhow!("{e}"))?,
+                _ => match lua
+                    .to_value(&format.parse(contents)?)
+                    .map_err(|e| anyhow::anyhow!("{e}"))?
+                {
+                    Value::Table(table) => table,
+                    _ => return Err(anyhow::anyhow!("expected a table at the top level")),
+                },
+            };
+
+            Self::merge_table(&table, &general, &listeners).map_err(|e| anyhow::anyhow!("{e}"))
+        })?;
+
+        let merged = lua.create_table().map_err(|e| anyhow::anyhow!("{e}"))?;
+        merged
+            .set("general", general)
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+        merged
+            .set("listeners", listeners)
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+
+        lua.from_value(Value::Table(merged))
+            .map_err(|e| anyhow::anyhow!("{e}"))
+    }
+
+    #[cfg(not(feature = "lua"))]
+    fn parse(path: &Path) -> anyhow::Result<Self> {
+        let mut general = serde_json::Map::new();
+        let mut listeners = Vec::new();
+        Self::load_file(path, &mut Vec::new(), &mut |format, contents| {
+            Self::merge_value(format.parse(contents)?, &mut general, &mut listeners)
+        })?;
+
+        Ok(serde_json::from_value(serde_json::json!({
+            "general": general,
+            "listeners": listeners,
+        }))?)
+    }
+
+    // Reads a config file and hands it to `merge`, which merges it into the config loaded so far
+    // and returns the files it includes. Those are then loaded the same way. Later `general`
+    // fields override earlier ones, listeners are appended.
+    fn load_file<F>(
+        path: &Path,
+        include_stack: &mut Vec<PathBuf>,
+        merge: &mut F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnMut(Format, &str) -> anyhow::Result<Vec<PathBuf>>,
+    {
+        let canonical_path = fs::canonicalize(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {e}", path.display()))?;
+        if include_stack.contains(&canonical_path) {
+            return Err(anyhow::anyhow!(
+                "Include cycle detected: {} is already being loaded",
+                path.display()
+            ));
+        }
+
+        let format = Format::from_path(path)?;
+        let contents = fs::read_to_string(&canonical_path)?;
+        let includes =
+            merge(format, &contents).map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+
+        let base_dir = canonical_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        include_stack.push(canonical_path);
+        for include in includes {
+            Self::load_file(&base_dir.join(include), include_stack, merge)?;
+        }
+        include_stack.pop();
+
+        Ok(())
+    }
+
+    #[cfg(feature = "lua")]
+    fn merge_table(
+        table: &Table,
+        general: &Table,
+        listeners: &Table,
+    ) -> mlua::Result<Vec<PathBuf>> {
+        if let Some(file_general) = table.get::<Option<Table>>("general")? {
+            for pair in file_general.pairs::<Value, Value>() {
+                let (key, value) = pair?;
+                general.set(key, value)?;
+            }
+        }
+
+        if let Some(file_listeners) = table.get::<Option<Table>>("listeners")? {
+            for listener in file_listeners.sequence_values::<Value>() {
+                listeners.push(listener?)?;
+            }
+        }
+
+        Ok(table
+            .get::<Option<Vec<String>>>("include")?
+            .unwrap_or_default()
+            .into_iter()
+            .map(PathBuf::from)
+            .collect())
+    }
+
+    #[cfg(not(feature = "lua"))]
+    fn merge_value(
+        value: serde_json::Value,
+        general: &mut serde_json::Map<String, serde_json::Value>,
+        listeners: &mut Vec<serde_json::Value>,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        use serde_json::Value;
+
+        let Value::Object(mut file) = value else {
+            return Err(anyhow::anyhow!("expected a table at the top level"));
+        };
+
+        match file.remove("general") {
+            Some(Value::Object(file_general)) => general.extend(file_general),
+            Some(Value::Null) | None => {}
+            Some(_) => return Err(anyhow::anyhow!("`general` has to be a table")),
+        }
+
+        match file.remove("listeners") {
+            Some(Value::Array(file_listeners)) => listeners.extend(file_listeners),
+            Some(Value::Null) | None => {}
+            Some(_) => return Err(anyhow::anyhow!("`listeners` has to be a list")),
+        }
+
+        Ok(file
+            .remove("include")
+            .map(serde_json::from_value::<Vec<PathBuf>>)
+            .transpose()?
+            .unwrap_or_default())
+    }
+
+    // Writes the example config to `path` or the default location, returning where it was written.
+    pub fn init(path: Option<PathBuf>, force: bool) -> anyhow::Result<PathBuf> {
         let config_path = if let Some(path) = path {
             path
         } else {
             Self::path()?
         };
-        let lua_code = fs::read_to_string(&config_path)?;
-        let lua = Lua::new();
-        let lua_result = lua
-            .load(&lua_code)
-            .eval()
-            .map_err(|e| anyhow::anyhow!("{e}"))?;
 
-        let config: Config = lua
-            .from_value(lua_result)
-            .map_err(|e| anyhow::anyhow!("{e}"))?;
+        if config_path.exists() && !force {
+            return Err(anyhow::anyhow!(
+                "{} already exists, use --force to overwrite it",
+                config_path.display()
+            ));
+        }
 
-        Ok((config.general, config.listeners))
+        if let Some(parent) = config_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&config_path, Format::from_path(&config_path)?.example()?)?;
+
+        Ok(config_path)
     }
 
     pub fn path() -> anyhow::Result<PathBuf> {
+        if let Some(path) = Self::env_path() {
+            return Ok(path);
+        }
+
+        if let Some(path) = Self::find()? {
+            return Ok(path);
+        }
+
+        let default_name = if cfg!(feature = "lua") {
+            "config.lua"
+        } else {
+            "config.toml"
+        };
+        let [_, standard_dir] = Self::dirs()?;
+        Ok(standard_dir.join(default_name))
+    }
+
+    // MOXIDLE_CONFIG takes precedence over the default locations, but not over --config.
+    fn env_path() -> Option<PathBuf> {
+        std::env::var_os("MOXIDLE_CONFIG")
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+    }
+
+    // Returns the first existing config file in the default locations.
+    fn find() -> anyhow::Result<Option<PathBuf>> {
+        Ok(Self::dirs()?.iter().find_map(|dir| {
+            Format::EXTENSIONS
+                .iter()
+                .map(|extension| dir.join("config").with_extension(extension))
+                .find(|path| path.exists())
+        }))
+    }
+
+    fn not_found() -> anyhow::Result<anyhow::Error> {
+        let tried = Self::dirs()?
+            .iter()
+            .map(|dir| {
+                format!(
+                    "{}/config.{{{}}}",
+                    dir.display(),
+                    Format::EXTENSIONS.join(",")
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" and ");
+
+        Ok(anyhow::anyhow!(
+            "No config found at {tried}, create one with --init or run without listeners using --allow-empty"
+        ))
+    }
+
+    // The mox directory takes precedence over the standard one.
+    fn dirs() -> anyhow::Result<[PathBuf; 2]> {
         let home_dir = std::env::var("HOME").map(PathBuf::from)?;
         let config_dir = std::env::var("XDG_CONFIG_HOME")
             .map(PathBuf::from)
             .unwrap_or_else(|_| home_dir.join(".config"));
 
-        let mox_path = config_dir.join("mox").join("moxidle").join("config.lua");
-        if mox_path.exists() {
-            return Ok(mox_path);
-        }
+        Ok([
+            config_dir.join("mox").join("moxidle"),
+            config_dir.join("moxidle"),
+        ])
+    }
+}
+
+// What happens to a command started while max_concurrent_commands are already running.
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandOverflow {
+    #[default]
+    Queue,
+    Drop,
+}
+
+// Which listeners fire when several reach their timeout during the same idle period.
+#[derive(Deserialize, Default, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenerPriority {
+    #[default]
+    All,
+    // Only the first listener to fire, in config order for equal timeouts, until activity.
+    First,
+}
 
-        let standard_path = config_dir.join("moxidle").join("config.lua");
-        if standard_path.exists() {
-            return Ok(standard_path);
+// How conditions relying on a service that isn't available are treated.
+#[derive(Deserialize, Default, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingService {
+    // The conditions are never met.
+    #[default]
+    Unmet,
+    // The conditions are left out, as if the listener didn't have them.
+    Ignore,
+}
+
+// How the idle state of multiple seats is combined, only the first seat is tracked when unset.
+#[derive(Deserialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SeatMode {
+    // Listeners fire once every seat is idle.
+    All,
+    // Listeners fire as soon as one seat is idle.
+    Any,
+}
+
+// Integrations that can be turned off entirely through `backends`.
+#[derive(Deserialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    Upower,
+    Login,
+    Screensaver,
+    Usb,
+    Audio,
+    Network,
+    Portal,
+}
+
+// A command line run through the shell, or a program and its arguments run directly.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Command {
+    Shell(Arc<str>),
+    Argv(Arc<[Arc<str>]>),
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Shell(command) => write!(f, "{command}"),
+            Self::Argv(argv) => write!(f, "{}", argv.join(" ")),
         }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Shell {
+    Path(Arc<str>),
+    Argv(Box<[Arc<str>]>),
+}
 
-        Ok(standard_path)
+impl Default for Shell {
+    fn default() -> Self {
+        Self::Path("/bin/sh".into())
+    }
+}
+
+impl Shell {
+    // Returns the program and the arguments preceding the command string.
+    pub fn argv(&self) -> Option<(&str, Vec<&str>)> {
+        match self {
+            Self::Path(path) => Some((path.as_ref(), vec!["-c"])),
+            Self::Argv(argv) => argv
+                .split_first()
+                .map(|(program, args)| (program.as_ref(), args.iter().map(|arg| &**arg).collect())),
+        }
     }
 }
 
 #[derive(Deserialize, Default)]
 #[serde(default)]
 pub struct MoxidleConfig {
-    pub lock_cmd: Option<Arc<str>>,
-    pub unlock_cmd: Option<Arc<str>>,
-    pub before_sleep_cmd: Option<Arc<str>>,
-    pub after_sleep_cmd: Option<Arc<str>>,
+    pub shell: Shell,
+    pub cwd: Option<PathBuf>,
+    pub max_concurrent_commands: Option<usize>,
+    pub command_overflow: CommandOverflow,
+    pub command_timeout_secs: Option<u64>,
+    pub on_start_cmd: Option<Command>,
+    pub lock_cmd: Option<Command>,
+    pub unlock_cmd: Option<Command>,
+    pub before_sleep_cmd: Option<Command>,
+    pub after_sleep_cmd: Option<Command>,
+    pub battery_device: Option<Arc<str>>,
+    pub missing_upower: MissingService,
+    pub on_battery_below: HashMap<u32, Command>,
+    pub on_battery_above: HashMap<u32, Command>,
+    pub on_throttle: Option<Command>,
+    pub on_unthrottle: Option<Command>,
+    pub on_lid_close: Option<Command>,
+    pub on_lid_open: Option<Command>,
+    pub on_compositor_lost: Option<Command>,
     pub ignore_dbus_inhibit: bool,
+    pub inhibit_allowlist: Option<Box<[Arc<str>]>>,
+    pub inhibit_denylist: Box<[Arc<str>]>,
+    pub max_inhibit_secs: Option<u64>,
+    pub replace_screensaver: Option<bool>,
+    pub require_active_session: bool,
     pub ignore_systemd_inhibit: bool,
-    #[cfg(feature = "audio")]
+    pub inhibit_on_fullscreen: bool,
+    #[cfg(feature = "portal")]
+    pub inhibit_on_screencast: bool,
+    pub detect_external_resume: Option<bool>,
+    pub seat_mode: Option<SeatMode>,
+    pub listener_priority: ListenerPriority,
+    #[cfg(any(feature = "audio", feature = "pipewire"))]
     pub ignore_audio_inhibit: bool,
+    #[cfg(any(feature = "audio", feature = "pipewire"))]
+    pub ignore_audio_capture: bool,
+    #[cfg(any(feature = "audio", feature = "pipewire"))]
+    pub audio_inhibit_roles: Option<Box<[Arc<str>]>>,
+    pub process_poll_interval: Option<u64>,
+    pub cpu_poll_interval: Option<u64>,
+    pub temp_poll_interval: Option<u64>,
+    pub usb_poll_ms: Option<u64>,
+    pub log_file: Option<PathBuf>,
+    pub log_max_size: Option<u64>,
+    pub event_socket: Option<PathBuf>,
+    #[cfg(feature = "metrics")]
+    pub metrics_address: Option<std::net::SocketAddr>,
+    pub backends: Option<Box<[Backend]>>,
+}
+
+impl MoxidleConfig {
+    // Every backend is enabled when `backends` is unset.
+    pub fn backend_enabled(&self, backend: Backend) -> bool {
+        self.backends
+            .as_ref()
+            .is_none_or(|backends| backends.contains(&backend))
+    }
+
+    pub fn replace_screensaver(&self) -> bool {
+        self.replace_screensaver.unwrap_or(true)
+    }
+
+    pub fn detect_external_resume(&self) -> bool {
+        self.detect_external_resume.unwrap_or(true)
+    }
+
+    pub fn max_concurrent_commands(&self) -> usize {
+        self.max_concurrent_commands.unwrap_or(16)
+    }
+
+    // Zero disables the timeout just like leaving it unset.
+    pub fn command_timeout(&self) -> Option<Duration> {
+        self.command_timeout_secs
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    pub fn process_poll_interval(&self) -> Duration {
+        Duration::from_secs(self.process_poll_interval.unwrap_or(5))
+    }
+
+    pub fn cpu_poll_interval(&self) -> Duration {
+        Duration::from_secs(self.cpu_poll_interval.unwrap_or(10))
+    }
+
+    pub fn temp_poll_interval(&self) -> Duration {
+        Duration::from_secs(self.temp_poll_interval.unwrap_or(10))
+    }
+
+    pub fn usb_poll_interval(&self) -> Duration {
+        Duration::from_millis(self.usb_poll_ms.unwrap_or(100))
+    }
 }
 
 #[derive(Deserialize, PartialEq, Debug)]
@@ -68,15 +703,348 @@ pub struct MoxidleConfig {
 pub enum Condition {
     OnBattery,
     OnAc,
-    BatteryBelow(f64),
-    BatteryAbove(f64),
+    ChargerOnline,
+    Charging,
+    Discharging,
+    FullyCharged,
+    BatteryBelow(BatteryThreshold),
+    BatteryAbove(BatteryThreshold),
+    #[serde(deserialize_with = "deserialize_percentage")]
     BatteryEqual(f64),
     #[serde(deserialize_with = "deserialize_battery_level")]
     BatteryLevel(BatteryLevel),
     #[serde(deserialize_with = "deserialize_battery_state")]
     BatteryState(BatteryState),
-    UsbPlugged(Arc<str>),
-    UsbUnplugged(Arc<str>),
+    TimeToEmptyBelow(u32),
+    #[serde(deserialize_with = "deserialize_usb_matcher")]
+    UsbPlugged(UsbMatcher),
+    #[serde(deserialize_with = "deserialize_usb_matcher")]
+    UsbUnplugged(UsbMatcher),
+    UsbClassPresent(Arc<str>),
+    UsbCountAtLeast(u32),
+    InputDevicePresent(Arc<str>),
+    ProcessRunning(Arc<str>),
+    CpuLoadAbove(f64),
+    TempAbove {
+        sensor: Arc<str>,
+        celsius: f64,
+    },
+    OutputConnected(Arc<str>),
+    MultipleOutputs,
+    LidClosed,
+    SessionLocked,
+    SessionUnlocked,
+    SessionActive,
+    ListenerFired(Arc<str>),
+    #[cfg(feature = "network")]
+    NetworkConnected,
+    #[cfg(feature = "network")]
+    Ssid(Arc<str>),
+    #[cfg(feature = "portal")]
+    ScreenCasting,
+    Not(Box<Condition>),
+    TimeRange {
+        start: String,
+        end: String,
+    },
+}
+
+enum BatteryThresholdConfig {
+    Plain(f64),
+    Table { threshold: f64, hysteresis: f64 },
+}
+
+#[derive(Deserialize)]
+struct BatteryThresholdTable {
+    #[serde(deserialize_with = "deserialize_percentage")]
+    threshold: f64,
+    #[serde(default)]
+    hysteresis: f64,
+}
+
+// Accepts "20" and "20%" as well as plain numbers.
+fn parse_percentage(value: &str) -> Option<f64> {
+    let value = value.trim();
+    value
+        .strip_suffix('%')
+        .unwrap_or(value)
+        .trim_end()
+        .parse()
+        .ok()
+}
+
+struct PercentageVisitor;
+
+impl serde::de::Visitor<'_> for PercentageVisitor {
+    type Value = f64;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a number or a percentage string like \"20%\"")
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(value)
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(value as f64)
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(value as f64)
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        parse_percentage(value)
+            .ok_or_else(|| E::custom(format!("Invalid battery percentage: {value}")))
+    }
+}
+
+fn deserialize_percentage<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(PercentageVisitor)
+}
+
+// Parses durations like "90s", "10m" or "1h30m", numbers may have decimals. Units are required
+// and have to go from largest to smallest, so that e.g. "10" can't be mistaken for minutes.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    const UNITS: [(&str, f64); 4] = [("h", 3600.0), ("m", 60.0), ("s", 1.0), ("ms", 0.001)];
+
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return Err("empty duration".into());
+    }
+
+    let mut secs = 0.0;
+    let mut next_unit = 0;
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let (number, after) = rest.split_at(number_len);
+        let after = after.trim_start();
+        let unit_len = after
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(after.len());
+        let (unit, after) = after.split_at(unit_len);
+
+        let number = number
+            .parse::<f64>()
+            .map_err(|_| format!("invalid duration '{value}'"))?;
+        let Some(index) = UNITS.iter().position(|(name, _)| *name == unit) else {
+            return Err(if unit.is_empty() {
+                format!("duration '{value}' is missing a unit, e.g. \"{number}s\"")
+            } else {
+                format!("unknown unit '{unit}' in duration '{value}', expected h, m, s or ms")
+            });
+        };
+        if index < next_unit {
+            return Err(format!(
+                "units of duration '{value}' have to go from largest to smallest without repeating"
+            ));
+        }
+
+        next_unit = index + 1;
+        secs += number * UNITS[index].1;
+        rest = after.trim_start();
+    }
+
+    Duration::try_from_secs_f64(secs).map_err(|e| format!("invalid duration '{value}': {e}"))
+}
+
+// Seconds as a number, or a duration string like "10m".
+fn deserialize_timeout<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct TimeoutVisitor;
+
+    impl serde::de::Visitor<'_> for TimeoutVisitor {
+        type Value = Duration;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("a number of seconds or a duration string like \"1h30m\"")
+        }
+
+        fn visit_f64<E>(self, value: f64) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Duration::try_from_secs_f64(value)
+                .map_err(|e| E::custom(format!("invalid timeout {value}: {e}")))
+        }
+
+        fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            u64::try_from(value)
+                .map(Duration::from_secs)
+                .map_err(|_| E::custom(format!("negative timeout {value}")))
+        }
+
+        fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Ok(Duration::from_secs(value))
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            parse_duration(value).map_err(E::custom)
+        }
+    }
+
+    deserializer.deserialize_any(TimeoutVisitor).map(Some)
+}
+
+impl<'de> Deserialize<'de> for BatteryThresholdConfig {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct BatteryThresholdVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for BatteryThresholdVisitor {
+            type Value = BatteryThresholdConfig;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str(
+                    "a number, a percentage string like \"20%\" or a table with a threshold",
+                )
+            }
+
+            fn visit_f64<E>(self, value: f64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(BatteryThresholdConfig::Plain(value))
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(BatteryThresholdConfig::Plain(value as f64))
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(BatteryThresholdConfig::Plain(value as f64))
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                parse_percentage(value)
+                    .map(BatteryThresholdConfig::Plain)
+                    .ok_or_else(|| E::custom(format!("Invalid battery percentage: {value}")))
+            }
+
+            fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                BatteryThresholdTable::deserialize(serde::de::value::MapAccessDeserializer::new(
+                    map,
+                ))
+                .map(|table| BatteryThresholdConfig::Table {
+                    threshold: table.threshold,
+                    hysteresis: table.hysteresis,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(BatteryThresholdVisitor)
+    }
+}
+
+// Once met, the condition stays met until the percentage is `hysteresis` past the threshold,
+// so listeners don't flap while the percentage wobbles around it.
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+#[serde(from = "BatteryThresholdConfig")]
+pub struct BatteryThreshold {
+    threshold: f64,
+    hysteresis: f64,
+}
+
+impl From<BatteryThresholdConfig> for BatteryThreshold {
+    fn from(config: BatteryThresholdConfig) -> Self {
+        let (threshold, hysteresis) = match config {
+            BatteryThresholdConfig::Plain(threshold) => (threshold, 0.0),
+            BatteryThresholdConfig::Table {
+                threshold,
+                hysteresis,
+            } => (threshold, hysteresis),
+        };
+
+        Self {
+            threshold,
+            hysteresis,
+        }
+    }
+}
+
+// `met` is whether the condition was met at the previous percentage.
+impl BatteryThreshold {
+    pub fn below(&self, percentage: f64, met: bool) -> bool {
+        let threshold = if met {
+            self.threshold + self.hysteresis
+        } else {
+            self.threshold
+        };
+        percentage < threshold
+    }
+
+    pub fn above(&self, percentage: f64, met: bool) -> bool {
+        let threshold = if met {
+            self.threshold - self.hysteresis
+        } else {
+            self.threshold
+        };
+        percentage > threshold
+    }
+}
+
+pub fn minutes_of_day(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    let hours: u32 = hours.parse().ok()?;
+    let minutes: u32 = minutes.parse().ok()?;
+
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+// Inclusive of start, exclusive of end. Ranges where end comes before start wrap past midnight.
+pub fn time_in_range(start: &str, end: &str, now: u32) -> bool {
+    let (Some(start), Some(end)) = (minutes_of_day(start), minutes_of_day(end)) else {
+        return false;
+    };
+
+    if start <= end {
+        start <= now && now < end
+    } else {
+        now >= start || now < end
+    }
 }
 
 #[derive(Debug)]
@@ -201,17 +1169,387 @@ where
     deserializer.deserialize_any(BatteryLevelVisitor)
 }
 
+#[derive(Debug)]
+pub struct InvalidUsbIdError;
+
+fn parse_usb_id(id: &str) -> Result<Option<u16>, InvalidUsbIdError> {
+    match id {
+        "*" => Ok(None),
+        id if (1..=4).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            u16::from_str_radix(id, 16)
+                .map(Some)
+                .map_err(|_| InvalidUsbIdError)
+        }
+        _ => Err(InvalidUsbIdError),
+    }
+}
+
+impl TryFrom<&str> for UsbMatcher {
+    type Error = InvalidUsbIdError;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let (vendor, product) = s.split_once(':').ok_or(InvalidUsbIdError)?;
+        if product.contains(':') {
+            return Err(InvalidUsbIdError);
+        }
+
+        Ok(UsbMatcher {
+            vendor: parse_usb_id(vendor)?,
+            product: parse_usb_id(product)?,
+            ..Default::default()
+        })
+    }
+}
+
+fn deserialize_usb_matcher<'de, D>(deserializer: D) -> Result<UsbMatcher, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct UsbMatcherVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for UsbMatcherVisitor {
+        type Value = UsbMatcher;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str(
+                "a vendor:product string or a table with vendor, product, serial and class fields",
+            )
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            UsbMatcher::try_from(value).map_err(|_| E::custom(format!("Invalid USB id: {value}")))
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+        where
+            A: serde::de::MapAccess<'de>,
+        {
+            let mut matcher = UsbMatcher::default();
+            while let Some(key) = map.next_key::<String>()? {
+                match key.as_str() {
+                    "vendor" | "product" => {
+                        let value = map.next_value::<String>()?;
+                        let id = parse_usb_id(&value).map_err(|_| {
+                            serde::de::Error::custom(format!("Invalid USB {key} id: {value}"))
+                        })?;
+                        if key == "vendor" {
+                            matcher.vendor = id;
+                        } else {
+                            matcher.product = id;
+                        }
+                    }
+                    "serial" => matcher.serial = Some(map.next_value::<String>()?.into()),
+                    "class" => matcher.class = Some(map.next_value()?),
+                    _ => {
+                        return Err(serde::de::Error::unknown_field(
+                            &key,
+                            &["vendor", "product", "serial", "class"],
+                        ));
+                    }
+                }
+            }
+            Ok(matcher)
+        }
+    }
+
+    deserializer.deserialize_any(UsbMatcherVisitor)
+}
+
+impl Condition {
+    // Resolves negations and passes every other condition to `condition_met`.
+    pub fn evaluate<F>(&self, condition_met: &mut F) -> bool
+    where
+        F: FnMut(&Condition) -> bool,
+    {
+        match self {
+            Condition::Not(inner) => !inner.evaluate(condition_met),
+            condition => condition_met(condition),
+        }
+    }
+
+    // Whether this condition, or the one it negates, matches `predicate`.
+    pub fn contains<F>(&self, predicate: &F) -> bool
+    where
+        F: Fn(&Condition) -> bool,
+    {
+        match self {
+            Condition::Not(inner) => inner.contains(predicate),
+            condition => predicate(condition),
+        }
+    }
+
+    // The condition with any negations removed.
+    pub fn base(&self) -> &Condition {
+        match self {
+            Condition::Not(inner) => inner.base(),
+            condition => condition,
+        }
+    }
+
+    // Name of the listener this condition, or the one it negates, depends on.
+    fn dependency(&self) -> Option<&Arc<str>> {
+        match self {
+            Condition::ListenerFired(name) => Some(name),
+            Condition::Not(inner) => inner.dependency(),
+            _ => None,
+        }
+    }
+
+    // Whether this condition, or the one it negates, is tracked through UPower.
+    pub fn needs_upower(&self) -> bool {
+        self.contains(&|condition| {
+            matches!(
+                condition,
+                Condition::OnBattery
+                    | Condition::OnAc
+                    | Condition::ChargerOnline
+                    | Condition::Charging
+                    | Condition::Discharging
+                    | Condition::FullyCharged
+                    | Condition::BatteryBelow(_)
+                    | Condition::BatteryAbove(_)
+                    | Condition::BatteryEqual(_)
+                    | Condition::BatteryLevel(_)
+                    | Condition::BatteryState(_)
+                    | Condition::TimeToEmptyBelow(_)
+            )
+        })
+    }
+
+    // Temperature sensor this condition, or the one it negates, reads.
+    pub fn temp_sensor(&self) -> Option<&Arc<str>> {
+        match self {
+            Condition::TempAbove { sensor, .. } => Some(sensor),
+            Condition::Not(inner) => inner.temp_sensor(),
+            _ => None,
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            Condition::TimeRange { start, end } => {
+                [start, end]
+                    .into_iter()
+                    .try_for_each(|time| match minutes_of_day(time) {
+                        Some(_) => Ok(()),
+                        None => Err(anyhow::anyhow!("invalid time '{time}', expected HH:MM")),
+                    })
+            }
+            Condition::BatteryBelow(battery) | Condition::BatteryAbove(battery)
+                if battery.hysteresis < 0.0 =>
+            {
+                Err(anyhow::anyhow!(
+                    "negative battery hysteresis {}",
+                    battery.hysteresis
+                ))
+            }
+            Condition::UsbClassPresent(class) if usb::class_code(class).is_none() => {
+                Err(anyhow::anyhow!("unknown USB class '{class}'"))
+            }
+            Condition::Not(inner) => inner.validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Default, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ConditionMatch {
+    #[default]
+    All,
+    Any,
+}
+
 #[derive(Deserialize)]
 pub struct ListenerConfig {
+    pub name: Option<Arc<str>>,
+    pub enabled: Option<bool>,
     #[serde(default)]
     pub conditions: Box<[Condition]>,
-    pub timeout: u32,
-    pub on_timeout: Option<Arc<str>>,
-    pub on_resume: Option<Arc<str>>,
+    #[serde(default, rename = "match")]
+    pub condition_match: ConditionMatch,
+    #[serde(default, deserialize_with = "deserialize_timeout")]
+    pub timeout: Option<Duration>,
+    pub timeout_ms: Option<u32>,
+    pub timeout_jitter_secs: Option<u32>,
+    pub cwd: Option<PathBuf>,
+    pub grace_ms: Option<u32>,
+    pub cooldown_secs: Option<u32>,
+    #[serde(default)]
+    pub ignore_inhibitors: bool,
+    pub respects_inhibitors: Option<bool>,
+    pub warn_before_secs: Option<u32>,
+    pub on_warn: Option<ListenerCommand>,
+    pub on_timeout: Option<ListenerCommand>,
+    pub on_resume: Option<ListenerCommand>,
+    pub sets_lock_state: Option<bool>,
+    #[serde(default)]
+    pub lock: bool,
+    pub lock_cmd: Option<Command>,
+    pub unlock_cmd: Option<Command>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PowerSourceCommand {
+    pub battery: Option<Command>,
+    pub ac: Option<Command>,
+}
+
+// Either a plain command or one per power source, picked when the listener fires or resumes.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum ListenerCommand {
+    Always(Command),
+    PowerSource(PowerSourceCommand),
+}
+
+impl ListenerCommand {
+    pub fn resolve(&self, on_battery: bool) -> Option<&Command> {
+        match self {
+            Self::Always(cmd) => Some(cmd),
+            Self::PowerSource(cmds) if on_battery => cmds.battery.as_ref(),
+            Self::PowerSource(cmds) => cmds.ac.as_ref(),
+        }
+    }
+}
+
+impl Display for ListenerCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Always(cmd) => write!(f, "{cmd}"),
+            Self::PowerSource(cmds) => {
+                let describe = |cmd: Option<&Command>| {
+                    cmd.map_or_else(|| "none".to_string(), ToString::to_string)
+                };
+                write!(
+                    f,
+                    "battery: {}, ac: {}",
+                    describe(cmds.battery.as_ref()),
+                    describe(cmds.ac.as_ref())
+                )
+            }
+        }
+    }
 }
 
 impl ListenerConfig {
+    // Conditions matching `skip` are left out, the listener counts as having none if all are.
+    pub fn conditions_met<S, F>(&self, skip: S, mut condition_met: F) -> bool
+    where
+        S: Fn(&Condition) -> bool,
+        F: FnMut(&Condition) -> bool,
+    {
+        let mut conditions = self
+            .conditions
+            .iter()
+            .filter(|condition| !skip(condition))
+            .peekable();
+        if conditions.peek().is_none() {
+            return true;
+        }
+
+        match self.condition_match {
+            ConditionMatch::All => {
+                conditions.all(|condition| condition.evaluate(&mut condition_met))
+            }
+            ConditionMatch::Any => {
+                conditions.any(|condition| condition.evaluate(&mut condition_met))
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    // Whether firing marks the session locked and resuming marks it unlocked.
+    pub fn sets_lock_state(&self) -> bool {
+        self.sets_lock_state.unwrap_or(true)
+    }
+
+    // respects_inhibitors = false is the same as ignore_inhibitors = true.
+    pub fn ignores_inhibitors(&self) -> bool {
+        self.ignore_inhibitors || self.respects_inhibitors == Some(false)
+    }
+
     pub fn timeout_millis(&self) -> u32 {
-        self.timeout * 1000
+        self.timeout_ms.unwrap_or_else(|| {
+            self.timeout
+                .map_or(0, |timeout| timeout.as_millis().min(u32::MAX.into()) as u32)
+        })
+    }
+
+    // Idle time after which on_warn runs, None without a warning or if it isn't before the timeout.
+    pub fn warn_timeout_millis(&self) -> Option<u32> {
+        self.warn_timeout_millis_for(self.timeout_millis())
+    }
+
+    // Same as warn_timeout_millis, relative to the timeout actually in effect after jitter.
+    pub fn warn_timeout_millis_for(&self, timeout_millis: u32) -> Option<u32> {
+        self.warn_before_secs
+            .and_then(|secs| timeout_millis.checked_sub(secs.saturating_mul(1000)))
+            .filter(|&timeout| timeout > 0)
+    }
+
+    // Whether both listeners arm at the same time, regardless of the order of their conditions.
+    fn same_trigger(&self, other: &Self) -> bool {
+        self.is_enabled()
+            && other.is_enabled()
+            && self.timeout_millis() == other.timeout_millis()
+            && self.condition_match == other.condition_match
+            && self.conditions.len() == other.conditions.len()
+            && self
+                .conditions
+                .iter()
+                .all(|condition| other.conditions.contains(condition))
+    }
+}
+
+// Returns the 1-based indices of listener pairs sharing a timeout and conditions, which is
+// almost always a copy-paste mistake.
+pub fn duplicate_listeners(listeners: &[ListenerConfig]) -> Vec<(usize, usize)> {
+    listeners
+        .iter()
+        .enumerate()
+        .flat_map(|(i, listener)| {
+            listeners
+                .iter()
+                .enumerate()
+                .skip(i + 1)
+                .filter(|(_, other)| listener.same_trigger(other))
+                .map(move |(j, _)| (i + 1, j + 1))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn any_match_needs_one_condition() {
+        let listener: ListenerConfig = toml::from_str(
+            r#"
+            timeout = 300
+            match = "any"
+            conditions = ["on_battery", "on_ac"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(listener.condition_match, ConditionMatch::Any);
+        assert_eq!(
+            *listener.conditions,
+            [Condition::OnBattery, Condition::OnAc]
+        );
+
+        // on_battery and on_ac exclude each other, only one of them is ever met.
+        assert!(listener.conditions_met(|_| false, |condition| *condition == Condition::OnBattery));
+        assert!(listener.conditions_met(|_| false, |condition| *condition == Condition::OnAc));
+        assert!(!listener.conditions_met(|_| false, |_| false));
     }
 }
@@ -0,0 +1,323 @@
+use crate::{Event, LockState, PresenceMode, retry, screensaver};
+use calloop::channel;
+use futures_lite::StreamExt;
+use std::sync::{
+    Arc,
+    atomic::{AtomicU32, Ordering},
+};
+use tokio::sync::{Mutex, mpsc, oneshot};
+use zbus::object_server::SignalEmitter;
+
+pub enum Signal {
+    InhibitedChanged(bool),
+    BatteryPercentageChanged,
+}
+
+#[derive(Debug)]
+struct Inhibitor {
+    cookie: u32,
+    reason: Box<str>,
+    client: zbus::names::UniqueName<'static>,
+}
+
+#[derive(Clone)]
+struct Control {
+    inhibitors: Arc<Mutex<Vec<Inhibitor>>>,
+    screensaver_inhibitors: Arc<Mutex<Vec<screensaver::Inhibitor>>>,
+    last_cookie: Arc<AtomicU32>,
+    event_sender: channel::Sender<Event>,
+}
+
+#[zbus::interface(name = "org.moxidle.Control")]
+impl Control {
+    #[zbus(signal)]
+    async fn inhibited_changed(
+        signal_emitter: &SignalEmitter<'_>,
+        inhibited: bool,
+    ) -> zbus::Result<()>;
+
+    async fn get_lock_state(&self) -> &'static str {
+        let (response_tx, response_rx) = oneshot::channel();
+        if let Err(e) = self.event_sender.send(Event::GetLockState(response_tx)) {
+            log::error!("Failed to send GetLockState request: {e}");
+            return "unlocked";
+        }
+
+        match response_rx.await.unwrap_or(LockState::Unlocked) {
+            LockState::Locked => "locked",
+            LockState::Unlocked => "unlocked",
+        }
+    }
+
+    async fn get_active_time(&self) -> u32 {
+        let (response_tx, response_rx) = oneshot::channel();
+        if let Err(e) = self.event_sender.send(Event::GetActiveTime(response_tx)) {
+            log::error!("Failed to send GetActiveTime request: {e}");
+            return 0;
+        }
+        response_rx.await.unwrap_or(0)
+    }
+
+    async fn get_seat_idle(&self) -> bool {
+        let (response_tx, response_rx) = oneshot::channel();
+        if let Err(e) = self.event_sender.send(Event::GetSeatIdle(response_tx)) {
+            log::error!("Failed to send GetSeatIdle request: {e}");
+            return false;
+        }
+        response_rx.await.unwrap_or(false)
+    }
+
+    async fn get_battery_percentage(&self) -> f64 {
+        let (response_tx, response_rx) = oneshot::channel();
+        if let Err(e) = self
+            .event_sender
+            .send(Event::GetBatteryPercentage(response_tx))
+        {
+            log::error!("Failed to send GetBatteryPercentage request: {e}");
+            return 0.0;
+        }
+        response_rx.await.unwrap_or(0.0)
+    }
+
+    // Timeout in seconds, whether the idle notification is armed, whether the conditions are
+    // met and the on_timeout command of every listener.
+    async fn list_listeners(&self) -> Vec<(u32, bool, bool, String)> {
+        let (response_tx, response_rx) = oneshot::channel();
+        if let Err(e) = self.event_sender.send(Event::GetListeners(response_tx)) {
+            log::error!("Failed to send GetListeners request: {e}");
+            return Vec::new();
+        }
+        response_rx.await.unwrap_or_default()
+    }
+
+    #[zbus(property)]
+    async fn battery_percentage(&self) -> f64 {
+        self.get_battery_percentage().await
+    }
+
+    async fn inhibit(
+        &mut self,
+        reason: &str,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+    ) -> u32 {
+        let cookie = self.last_cookie.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(sender) = header.sender() {
+            log::info!("Added control inhibitor {sender:?}, reason: {reason}, cookie: {cookie}");
+            let mut inhibitors = self.inhibitors.lock().await;
+            if inhibitors.is_empty()
+                && let Err(e) = self.event_sender.send(Event::ControlInhibit(true))
+            {
+                log::error!("Failed to send ControlInhibit event {e}");
+            }
+            inhibitors.push(Inhibitor {
+                cookie,
+                reason: reason.into(),
+                client: sender.to_owned(),
+            });
+        }
+        cookie
+    }
+
+    async fn uninhibit(&mut self, cookie: u32) {
+        let mut inhibitors = self.inhibitors.lock().await;
+        if let Some(idx) = inhibitors.iter().position(|x| x.cookie == cookie) {
+            let inhibitor = inhibitors.remove(idx);
+            if inhibitors.is_empty()
+                && let Err(e) = self.event_sender.send(Event::ControlInhibit(false))
+            {
+                log::error!("Failed to send ControlInhibit event {e}");
+            }
+            log::info!(
+                "Removed control inhibitor {:?}, reason: {}, cookie: {}",
+                inhibitor.client,
+                inhibitor.reason,
+                inhibitor.cookie
+            );
+        }
+    }
+
+    // Returns (application_name, reason, cookie) of every active screensaver inhibitor.
+    async fn list_inhibitors(&self) -> Vec<(String, String, u32)> {
+        self.screensaver_inhibitors
+            .lock()
+            .await
+            .iter()
+            .map(|inhibitor| {
+                (
+                    inhibitor.application_name.to_string(),
+                    inhibitor.reason_for_inhibit.to_string(),
+                    inhibitor.cookie,
+                )
+            })
+            .collect()
+    }
+
+    // Force-removes every screensaver inhibitor, for applications that leak them. Returns how
+    // many were removed.
+    async fn clear_inhibitors(&self) -> u32 {
+        let mut inhibitors = self.screensaver_inhibitors.lock().await;
+        for inhibitor in inhibitors.iter() {
+            log::info!(
+                "Cleared screensaver inhibitor for application '{}', cookie: {}",
+                inhibitor.application_name,
+                inhibitor.cookie
+            );
+        }
+
+        let count = inhibitors.len() as u32;
+        inhibitors.clear();
+        if count > 0
+            && let Err(e) = self.event_sender.send(Event::ScreenSaverInhibit(false))
+        {
+            log::error!("Failed to send ScreenSaverInhibit(false) event: {e}");
+        }
+        count
+    }
+
+    async fn reload(&self) -> zbus::fdo::Result<()> {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.event_sender
+            .send(Event::Reload(response_tx))
+            .map_err(|e| zbus::fdo::Error::Failed(format!("Failed to send Reload request: {e}")))?;
+
+        match response_rx.await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(zbus::fdo::Error::Failed(format!(
+                "Failed to reload config: {e}"
+            ))),
+            Err(e) => Err(zbus::fdo::Error::Failed(format!(
+                "Failed to receive reload result: {e}"
+            ))),
+        }
+    }
+
+    // One of "normal", "always_awake" or "force_idle".
+    async fn set_presence_mode(&self, mode: &str) -> zbus::fdo::Result<()> {
+        let mode = match mode {
+            "normal" => PresenceMode::Normal,
+            "always_awake" => PresenceMode::AlwaysAwake,
+            "force_idle" => PresenceMode::ForceIdle,
+            _ => {
+                return Err(zbus::fdo::Error::InvalidArgs(format!(
+                    "Unknown presence mode: {mode}"
+                )));
+            }
+        };
+
+        self.event_sender
+            .send(Event::SetPresenceMode(mode))
+            .map_err(|e| {
+                zbus::fdo::Error::Failed(format!("Failed to send SetPresenceMode event: {e}"))
+            })
+    }
+
+    async fn simulate_activity(&self) {
+        if let Err(e) = self.event_sender.send(Event::SimulateUserActivity) {
+            log::error!("Failed to send SimulateUserActivity event: {e}");
+        }
+    }
+}
+
+// Calls a method of a running moxidle instance, fails if none is running.
+async fn call(method: &str) -> zbus::Result<zbus::Message> {
+    zbus::Connection::session()
+        .await?
+        .call_method(
+            Some("org.moxidle.Control"),
+            "/org/moxidle/Control",
+            Some("org.moxidle.Control"),
+            method,
+            &(),
+        )
+        .await
+}
+
+// Resets the idle timers of a running moxidle instance.
+pub async fn simulate_activity() -> zbus::Result<()> {
+    call("SimulateActivity").await.map(|_| ())
+}
+
+// Application name, reason and cookie of the screensaver inhibitors of a running instance.
+pub async fn list_inhibitors() -> zbus::Result<Vec<(String, String, u32)>> {
+    call("ListInhibitors").await?.body().deserialize()
+}
+
+// Removes the screensaver inhibitors of a running instance, returns how many were removed.
+pub async fn clear_inhibitors() -> zbus::Result<u32> {
+    call("ClearInhibitors").await?.body().deserialize()
+}
+
+pub async fn serve(
+    event_sender: channel::Sender<Event>,
+    mut signal_receiver: mpsc::UnboundedReceiver<Signal>,
+    screensaver_inhibitors: Arc<Mutex<Vec<screensaver::Inhibitor>>>,
+) -> zbus::Result<()> {
+    let inhibitors = Arc::new(Mutex::new(Vec::new()));
+
+    let control = Control {
+        inhibitors: Arc::clone(&inhibitors),
+        screensaver_inhibitors,
+        event_sender: event_sender.clone(),
+        last_cookie: Arc::new(AtomicU32::new(0)),
+    };
+
+    let conn = retry::with_backoff("connect to the session bus", || async {
+        zbus::connection::Builder::session()?
+            .serve_at("/org/moxidle/Control", control.clone())?
+            .name("org.moxidle.Control")?
+            .build()
+            .await
+    })
+    .await?;
+
+    let interface = conn
+        .object_server()
+        .interface::<_, Control>("/org/moxidle/Control")
+        .await?;
+
+    tokio::spawn(async move {
+        while let Some(signal) = signal_receiver.recv().await {
+            let result = match signal {
+                Signal::InhibitedChanged(inhibited) => {
+                    Control::inhibited_changed(interface.signal_emitter(), inhibited).await
+                }
+                Signal::BatteryPercentageChanged => {
+                    interface
+                        .get()
+                        .await
+                        .battery_percentage_changed(interface.signal_emitter())
+                        .await
+                }
+            };
+
+            if let Err(e) = result {
+                log::error!("Failed to emit control signal: {e}");
+            }
+        }
+    });
+
+    let dbus = zbus::fdo::DBusProxy::new(&conn).await?;
+    let mut name_owner_stream = dbus.receive_name_owner_changed().await?;
+    tokio::spawn(async move {
+        // Keep the connection alive for as long as the interface is served.
+        let _conn = conn;
+        while let Some(event) = name_owner_stream.next().await {
+            if let Ok(args) = event.args()
+                && args.new_owner.is_none()
+                && let zbus::names::BusName::Unique(name) = args.name
+            {
+                let mut inhibitors = inhibitors.lock().await;
+                if !inhibitors.is_empty() {
+                    inhibitors.retain(|inhibitor| inhibitor.client != name);
+                    if inhibitors.is_empty()
+                        && let Err(e) = event_sender.send(Event::ControlInhibit(false))
+                    {
+                        log::error!("Failed to send ControlInhibit(false) event: {e}");
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,11 @@
+use std::{fs, thread};
+
+// 1-minute load average from /proc/loadavg divided by the number of available cores and
+// clamped to 0.0..=1.0, so a 4-core machine at load 4.0 or above reports 1.0.
+pub fn load() -> Option<f64> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    let load = loadavg.split_whitespace().next()?.parse::<f64>().ok()?;
+    let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+
+    Some((load / cores as f64).clamp(0.0, 1.0))
+}
@@ -0,0 +1,203 @@
+use crate::Moxidle;
+use calloop::{Interest, LoopHandle, Mode, PostAction, Readiness, generic::Generic};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Write},
+    net::Shutdown,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    rc::Rc,
+};
+
+// Output buffered for a client that isn't reading, it's dropped beyond this.
+const MAX_PENDING: usize = 64 * 1024;
+
+struct Client {
+    stream: Rc<UnixStream>,
+    pending: Vec<u8>,
+}
+
+impl Client {
+    // Writes as much of the pending output as the socket takes without blocking.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.stream.as_ref().write(&self.pending) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.pending.drain(..written);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // Discards whatever the client sent, returns false once it hung up.
+    fn drain(&self) -> io::Result<bool> {
+        let mut buf = [0; 512];
+        loop {
+            match self.stream.as_ref().read(&mut buf) {
+                Ok(0) => return Ok(false),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Returns false once the client hung up.
+    fn handle(&mut self, readiness: Readiness) -> io::Result<bool> {
+        if (readiness.readable || readiness.error) && !self.drain()? {
+            return Ok(false);
+        }
+        if readiness.writable {
+            self.flush()?;
+        }
+        Ok(true)
+    }
+}
+
+#[derive(Default)]
+pub struct Clients {
+    clients: HashMap<u64, Client>,
+    next_id: u64,
+}
+
+impl Clients {
+    // Queues `event` as a JSON line for every client and writes what the sockets take, the
+    // rest is flushed once they're writable again. Clients that disconnected or fall too far
+    // behind are dropped, events that can't be serialized (queries) are skipped.
+    pub fn broadcast<T: Serialize>(&mut self, event: &T) {
+        if self.clients.is_empty() {
+            return;
+        }
+
+        let Ok(mut line) = serde_json::to_vec(event) else {
+            return;
+        };
+        line.push(b'\n');
+
+        self.clients.retain(|_, client| {
+            if client.pending.len() + line.len() > MAX_PENDING {
+                log::debug!("Dropping event socket client that isn't reading");
+                shutdown(client);
+                return false;
+            }
+
+            client.pending.extend_from_slice(&line);
+            match client.flush() {
+                Ok(()) => true,
+                Err(e) => {
+                    log::debug!("Dropping event socket client: {e}");
+                    shutdown(client);
+                    false
+                }
+            }
+        });
+    }
+}
+
+// Wakes up the client's source so it removes itself.
+fn shutdown(client: &Client) {
+    _ = client.stream.shutdown(Shutdown::Both);
+}
+
+// Removes a socket left behind by a previous instance, binding fails otherwise.
+fn remove_stale(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "path exists and isn't a socket",
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn serve(path: &Path, loop_handle: &LoopHandle<'static, Moxidle>) -> anyhow::Result<()> {
+    remove_stale(path)
+        .map_err(|e| anyhow::anyhow!("Failed to bind event socket {}: {e}", path.display()))?;
+    let listener = UnixListener::bind(path)
+        .map_err(|e| anyhow::anyhow!("Failed to bind event socket {}: {e}", path.display()))?;
+    listener.set_nonblocking(true)?;
+    log::info!("Event socket listening on {}", path.display());
+
+    let client_handle = loop_handle.clone();
+    loop_handle
+        .insert_source(
+            Generic::new(listener, Interest::READ, Mode::Level),
+            move |_, listener, state| {
+                loop {
+                    match listener.as_ref().accept() {
+                        Ok((stream, _)) => match stream.set_nonblocking(true) {
+                            Ok(()) => add_client(&client_handle, &mut state.event_clients, stream),
+                            Err(e) => log::warn!("Failed to configure event socket client: {e}"),
+                        },
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            log::warn!("Failed to accept event socket client: {e}");
+                            break;
+                        }
+                    }
+                }
+
+                Ok(PostAction::Continue)
+            },
+        )
+        .map(|_| ())
+        .map_err(|e| anyhow::anyhow!("Failed to insert event socket source: {e}"))
+}
+
+// Registers the client with the loop to flush its pending output once the socket is writable
+// and to notice when it hangs up.
+fn add_client(
+    loop_handle: &LoopHandle<'static, Moxidle>,
+    clients: &mut Clients,
+    stream: UnixStream,
+) {
+    let id = clients.next_id;
+    clients.next_id += 1;
+    let stream = Rc::new(stream);
+
+    let source = Generic::new(Rc::clone(&stream), Interest::BOTH, Mode::Edge);
+    let inserted = loop_handle.insert_source(source, move |readiness, _, state| {
+        let clients = &mut state.event_clients.clients;
+        let Some(client) = clients.get_mut(&id) else {
+            return Ok(PostAction::Remove);
+        };
+
+        let connected = client.handle(readiness).unwrap_or_else(|e| {
+            log::debug!("Dropping event socket client: {e}");
+            false
+        });
+
+        if connected {
+            Ok(PostAction::Continue)
+        } else {
+            clients.remove(&id);
+            Ok(PostAction::Remove)
+        }
+    });
+
+    match inserted {
+        Ok(_) => {
+            clients.clients.insert(
+                id,
+                Client {
+                    stream,
+                    pending: Vec::new(),
+                },
+            );
+        }
+        Err(e) => log::warn!("Failed to insert event socket client source: {e}"),
+    }
+}
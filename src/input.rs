@@ -0,0 +1,17 @@
+use std::fs;
+
+const INPUT_DEVICES: &str = "/proc/bus/input/devices";
+
+// Whether an evdev input device whose name contains `name`, ignoring case, is present. This
+// covers keyboards and pointers on any bus, not only USB ones.
+pub fn is_present(name: &str) -> bool {
+    let Ok(devices) = fs::read_to_string(INPUT_DEVICES) else {
+        return false;
+    };
+
+    let name = name.to_lowercase();
+    devices
+        .lines()
+        .filter_map(|line| line.strip_prefix("N: Name="))
+        .any(|device| device.trim_matches('"').to_lowercase().contains(&name))
+}
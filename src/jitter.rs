@@ -0,0 +1,38 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Xorshift generator seeded once per process, only used to spread listener timeouts so that
+// machines sharing a config don't all act at the same moment.
+pub struct Jitter {
+    state: u64,
+}
+
+impl Default for Jitter {
+    fn default() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos())
+            .unwrap_or_default();
+        let seed = RandomState::new().hash_one((nanos, std::process::id()));
+        // Xorshift gets stuck on zero.
+        Self { state: seed | 1 }
+    }
+}
+
+impl Jitter {
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    // Moves the timeout by up to `jitter_secs` in either direction, never below 1ms.
+    pub fn apply(&mut self, timeout_millis: u32, jitter_secs: u32) -> u32 {
+        let jitter_millis = i64::from(jitter_secs) * 1000;
+        let offset = (self.next() % (2 * jitter_millis as u64 + 1)) as i64 - jitter_millis;
+        (i64::from(timeout_millis) + offset).clamp(1, i64::from(u32::MAX)) as u32
+    }
+}
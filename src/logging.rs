@@ -0,0 +1,138 @@
+use env_logger::{Builder, Target};
+use log::LevelFilter;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+// Writes logs to stderr and appends them to a file, which is renamed to `<path>.1` once it
+// grows past `max_size`, replacing the previous one.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, max_size: u64) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        _ = io::stderr().write_all(buf);
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        if self.size > self.max_size
+            && let Err(e) = self.rotate()
+        {
+            _ = writeln!(io::stderr(), "Failed to rotate log file: {e}");
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Forwards records to the journal, applying the same filter env_logger is configured with.
+#[cfg(feature = "journald")]
+struct JournalLogger {
+    journal: systemd_journal_logger::JournalLog,
+    level: LevelFilter,
+}
+
+#[cfg(feature = "journald")]
+impl log::Log for JournalLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        let target = metadata.target();
+        (target == "moxidle" || target.starts_with("moxidle::")) && metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            self.journal.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.journal.flush();
+    }
+}
+
+// Relative paths are resolved against $XDG_STATE_HOME/moxidle.
+fn resolve_path(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+
+    let state_dir = std::env::var("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .unwrap_or_default();
+
+    state_dir.join("moxidle").join(path)
+}
+
+pub fn init(
+    level: LevelFilter,
+    log_file: Option<&Path>,
+    max_size: Option<u64>,
+) -> anyhow::Result<()> {
+    // systemd sets $JOURNAL_STREAM when stderr is connected to the journal.
+    #[cfg(feature = "journald")]
+    if log_file.is_none() && std::env::var_os("JOURNAL_STREAM").is_some() {
+        let journal = systemd_journal_logger::JournalLog::new()?;
+        log::set_boxed_logger(Box::new(JournalLogger { journal, level }))?;
+        log::set_max_level(level);
+        return Ok(());
+    }
+
+    let mut builder = Builder::new();
+    builder.filter(Some("moxidle"), level);
+
+    if let Some(path) = log_file {
+        let path = resolve_path(path);
+        let log_file = LogFile::open(path.clone(), max_size.unwrap_or(DEFAULT_MAX_SIZE))
+            .map_err(|e| anyhow::anyhow!("Failed to open log file {}: {e}", path.display()))?;
+        builder.target(Target::Pipe(Box::new(log_file)));
+    }
+
+    builder.init();
+
+    Ok(())
+}
@@ -1,7 +1,8 @@
-use crate::Event;
+use crate::{Event, retry, supervise};
 use calloop::channel;
 use futures_lite::StreamExt;
 use std::sync::Arc;
+use tokio::task::JoinSet;
 
 #[zbus::proxy(
     interface = "org.freedesktop.login1.Manager",
@@ -14,6 +15,9 @@ trait LoginManager {
     #[zbus(property)]
     fn block_inhibited(&self) -> zbus::Result<String>;
 
+    #[zbus(property)]
+    fn lid_closed(&self) -> zbus::Result<bool>;
+
     #[zbus(signal)]
     async fn prepare_for_sleep(&self, start: bool) -> zbus::Result<()>;
 }
@@ -23,6 +27,9 @@ trait LoginManager {
     default_service = "org.freedesktop.login1"
 )]
 trait LoginSession {
+    #[zbus(property)]
+    fn active(&self) -> zbus::Result<bool>;
+
     #[zbus(signal)]
     fn lock(&self) -> zbus::Result<bool>;
 
@@ -30,6 +37,18 @@ trait LoginSession {
     fn unlock(&self) -> zbus::Result<bool>;
 }
 
+fn handle_lid_closed(value: bool, sender: &channel::Sender<Event>) {
+    if let Err(e) = sender.send(Event::LidClosed(value)) {
+        log::error!("Failed to send LidClosed event: {e}");
+    }
+}
+
+fn handle_session_active(value: bool, sender: &channel::Sender<Event>) {
+    if let Err(e) = sender.send(Event::SessionActive(value)) {
+        log::error!("Failed to send SessionActive event: {e}");
+    }
+}
+
 async fn handle_block_inhibited(value: &str, sender: &channel::Sender<Event>) {
     if let Err(e) = sender.send(Event::BlockInhibited(value.contains("idle"))) {
         log::error!("Failed to send BlockInhibited event: {e}");
@@ -40,9 +59,16 @@ pub async fn serve(
     connection: Arc<zbus::Connection>,
     event_sender: channel::Sender<Event>,
     ignore_systemd_inhibit: bool,
-) -> zbus::Result<()> {
-    let login_manager = Arc::new(LoginManagerProxy::new(&connection).await?);
-    let session_path = login_manager.get_session("auto").await?;
+    ignore_lid: bool,
+    ignore_session_active: bool,
+) -> anyhow::Result<()> {
+    let login_manager = Arc::new(
+        retry::with_backoff("connect to logind", || LoginManagerProxy::new(&connection)).await?,
+    );
+    let session_path = retry::with_backoff("get the logind session", || {
+        login_manager.get_session("auto")
+    })
+    .await?;
 
     let login_session = match LoginSessionProxy::builder(&connection)
         .path(session_path)?
@@ -56,10 +82,12 @@ pub async fn serve(
         }
     };
 
+    let mut tasks = JoinSet::new();
+
     if !ignore_systemd_inhibit {
         let event_sender = event_sender.clone();
         let login_manager = Arc::clone(&login_manager);
-        tokio::spawn(async move {
+        tasks.spawn(async move {
             let mut block_inhibited_stream = login_manager.receive_block_inhibited_changed().await;
             while let Some(change) = block_inhibited_stream.next().await {
                 if change.name() == "BlockInhibited"
@@ -71,10 +99,53 @@ pub async fn serve(
         });
     }
 
+    if !ignore_lid {
+        // Subscribed before reading so that a lid change in between isn't missed.
+        let mut lid_closed_stream = login_manager.receive_lid_closed_changed().await;
+        match login_manager.lid_closed().await {
+            Ok(lid_closed) => {
+                log::info!("LidClosed listener active");
+                handle_lid_closed(lid_closed, &event_sender);
+
+                let event_sender = event_sender.clone();
+                tasks.spawn(async move {
+                    while let Some(change) = lid_closed_stream.next().await {
+                        if let Ok(lid_closed) = change.get().await {
+                            handle_lid_closed(lid_closed, &event_sender);
+                        }
+                    }
+                });
+            }
+            // Not every logind implementation exposes the lid state.
+            Err(e) => log::info!("Lid state unavailable, lid events are disabled: {e}"),
+        }
+    }
+
+    if !ignore_session_active {
+        // Subscribed before reading so that a session switch in between isn't missed.
+        let mut active_stream = login_session.receive_active_changed().await;
+        match login_session.active().await {
+            Ok(active) => {
+                log::info!("SessionActive listener active");
+                handle_session_active(active, &event_sender);
+            }
+            Err(e) => log::warn!("Failed to get session active state: {e}"),
+        }
+
+        let event_sender = event_sender.clone();
+        tasks.spawn(async move {
+            while let Some(change) = active_stream.next().await {
+                if let Ok(active) = change.get().await {
+                    handle_session_active(active, &event_sender);
+                }
+            }
+        });
+    }
+
     {
         let event_sender = event_sender.clone();
         let login_session = Arc::clone(&login_session);
-        tokio::spawn(async move {
+        tasks.spawn(async move {
             let mut lock_stream = login_session.receive_lock().await.unwrap();
             while lock_stream.next().await.is_some() {
                 if let Err(e) = event_sender.send(Event::SessionLocked(true)) {
@@ -86,7 +157,7 @@ pub async fn serve(
 
     {
         let event_sender = event_sender.clone();
-        tokio::spawn(async move {
+        tasks.spawn(async move {
             let mut unlock_stream = login_session.receive_unlock().await.unwrap();
             while unlock_stream.next().await.is_some() {
                 if let Err(e) = event_sender.send(Event::SessionLocked(false)) {
@@ -98,7 +169,7 @@ pub async fn serve(
 
     {
         let event_sender = event_sender.clone();
-        tokio::spawn(async move {
+        tasks.spawn(async move {
             let mut sleep_stream = login_manager.receive_prepare_for_sleep().await.unwrap();
             while let Some(sleep) = sleep_stream.next().await {
                 if let Ok(sleep) = sleep.args() {
@@ -111,5 +182,5 @@ pub async fn serve(
         });
     }
 
-    Ok(())
+    supervise::join(tasks).await
 }
@@ -1,75 +1,306 @@
-#[cfg(feature = "audio")]
+#[cfg(all(feature = "audio", not(feature = "pipewire")))]
 mod audio;
+mod command;
 mod config;
+mod control;
+mod cpu;
+mod event_socket;
+mod input;
+mod jitter;
+mod logging;
 mod login;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "network")]
+mod network;
+mod output;
+#[cfg(feature = "pipewire")]
+mod pipewire;
+#[cfg(feature = "portal")]
+mod portal;
+mod process;
+mod retry;
 mod screensaver;
+mod state_file;
+mod supervise;
+mod thermal;
+mod toplevel;
 mod upower;
 mod usb;
 
-use calloop::EventLoop;
+use calloop::{
+    EventLoop, Interest, LoopHandle, Mode, PostAction, RegistrationToken,
+    generic::Generic,
+    signals::{Signal, Signals},
+};
 use calloop_wayland_source::WaylandSource;
+use chrono::Timelike;
 use clap::Parser;
+use command::{CommandContext, CommandRunner};
 use config::Condition;
-use config::{Config, ListenerConfig, MoxidleConfig};
-use env_logger::Builder;
+use config::{
+    Backend, BatteryThreshold, Config, ListenerConfig, ListenerPriority, MissingService,
+    MoxidleConfig, SeatMode,
+};
 use log::LevelFilter;
 use rusb::UsbContext;
-use std::process::{Command, Stdio};
+use serde::Serialize;
 use std::sync::mpsc;
-use std::{ops::Deref, path::PathBuf, sync::Arc, time::Instant};
-use tokio::sync::oneshot;
+use std::{
+    collections::HashSet,
+    ops::Deref,
+    os::fd::BorrowedFd,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc as tokio_mpsc, oneshot};
 use upower::{BatteryLevel, BatteryState, LevelComparison, Power, PowerSource};
 use wayland_client::{
-    Connection, Dispatch, QueueHandle, delegate_noop,
+    Connection, Dispatch, Proxy, QueueHandle, delegate_noop,
     globals::{GlobalList, GlobalListContents, registry_queue_init},
     protocol::{wl_pointer, wl_registry, wl_seat},
 };
 use wayland_protocols::ext::idle_notify::v1::client::{
     ext_idle_notification_v1, ext_idle_notifier_v1,
 };
+use wayland_protocols_wlr::{
+    foreign_toplevel::v1::client::zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1,
+    output_management::v1::client::zwlr_output_manager_v1::ZwlrOutputManagerV1,
+};
+
+// Idle notifications for one timeout, one per tracked seat.
+#[derive(Default)]
+struct SeatNotifications {
+    // Empty while not armed.
+    notifications: Vec<ext_idle_notification_v1::ExtIdleNotificationV1>,
+    // Idle state of every seat, indexed like `notifications`.
+    idle: Vec<bool>,
+}
+
+impl SeatNotifications {
+    fn is_armed(&self) -> bool {
+        !self.notifications.is_empty()
+    }
+
+    fn arm(&mut self, notifications: Vec<ext_idle_notification_v1::ExtIdleNotificationV1>) {
+        self.idle = vec![false; notifications.len()];
+        self.notifications = notifications;
+    }
+
+    fn disarm(&mut self) {
+        self.notifications
+            .drain(..)
+            .for_each(|notification| notification.destroy());
+        self.idle.clear();
+    }
+
+    fn seat(
+        &self,
+        notification: &ext_idle_notification_v1::ExtIdleNotificationV1,
+    ) -> Option<usize> {
+        self.notifications.iter().position(|n| n == notification)
+    }
+
+    // Whether the seats count as idle, combined according to seat_mode.
+    fn is_idle(&self, seat_mode: Option<SeatMode>) -> bool {
+        match seat_mode {
+            Some(SeatMode::All) => !self.idle.is_empty() && self.idle.iter().all(|idle| *idle),
+            Some(SeatMode::Any) | None => self.idle.iter().any(|idle| *idle),
+        }
+    }
+
+    // Records the state of a seat, returns whether the combined state changed.
+    fn update(&mut self, seat: usize, idle: bool, seat_mode: Option<SeatMode>) -> bool {
+        let was_idle = self.is_idle(seat_mode);
+        self.idle[seat] = idle;
+        self.is_idle(seat_mode) != was_idle
+    }
+}
 
 struct TimeoutHandler {
     config: ListenerConfig,
-    notification: Option<ext_idle_notification_v1::ExtIdleNotificationV1>,
+    notifications: SeatNotifications,
+    // Fire warn_before_secs ahead of `notifications` to run on_warn.
+    warn_notifications: SeatNotifications,
+    idled: bool,
+    grace_timer: Option<RegistrationToken>,
+    last_fired: Option<Instant>,
+    // Set when an idle event was suppressed by the cooldown, so the matching resume is too.
+    suppressed: bool,
+    fired_count: u64,
+    // Timeout the notifications were last armed with, timeout_jitter_secs included.
+    timeout: u32,
+    // battery_below and battery_above thresholds met at the last percentage change, which stay
+    // met within their hysteresis.
+    below_met: Vec<BatteryThreshold>,
+    above_met: Vec<BatteryThreshold>,
 }
 
 impl TimeoutHandler {
     fn new(config: ListenerConfig) -> Self {
         Self {
+            timeout: config.timeout_millis(),
             config,
-            notification: None,
+            notifications: SeatNotifications::default(),
+            warn_notifications: SeatNotifications::default(),
+            idled: false,
+            grace_timer: None,
+            last_fired: None,
+            suppressed: false,
+            fired_count: 0,
+            below_met: Vec::new(),
+            above_met: Vec::new(),
         }
     }
 
-    fn on_timeout(&self) -> Option<&Arc<str>> {
-        self.config.on_timeout.as_ref()
+    // Only done when the percentage changes, evaluating the conditions leaves the state alone.
+    fn update_battery_thresholds(&mut self, percentage: f64) {
+        let mut below_met = Vec::new();
+        let mut above_met = Vec::new();
+        self.config
+            .conditions
+            .iter()
+            .map(Condition::base)
+            .for_each(|condition| match condition {
+                Condition::BatteryBelow(battery)
+                    if battery.below(percentage, self.below_met.contains(battery)) =>
+                {
+                    below_met.push(battery.clone());
+                }
+                Condition::BatteryAbove(battery)
+                    if battery.above(percentage, self.above_met.contains(battery)) =>
+                {
+                    above_met.push(battery.clone());
+                }
+                _ => {}
+            });
+        self.below_met = below_met;
+        self.above_met = above_met;
+    }
+
+    fn in_cooldown(&self) -> bool {
+        self.config
+            .cooldown_secs
+            .zip(self.last_fired)
+            .is_some_and(|(secs, last_fired)| {
+                last_fired.elapsed() < Duration::from_secs(secs.into())
+            })
+    }
+
+    fn is_armed(&self) -> bool {
+        self.notifications.is_armed()
     }
 
-    fn on_resume(&self) -> Option<&Arc<str>> {
-        self.config.on_resume.as_ref()
+    fn disarm(&mut self) {
+        self.notifications.disarm();
+        self.warn_notifications.disarm();
+    }
+
+    fn on_timeout(&self, power: &Power) -> Option<&config::Command> {
+        self.config
+            .on_timeout
+            .as_ref()
+            .and_then(|cmd| cmd.resolve(power.source() == &PowerSource::Battery))
+    }
+
+    fn on_resume(&self, power: &Power) -> Option<&config::Command> {
+        self.config
+            .on_resume
+            .as_ref()
+            .and_then(|cmd| cmd.resolve(power.source() == &PowerSource::Battery))
+    }
+
+    fn on_warn(&self, power: &Power) -> Option<&config::Command> {
+        self.config
+            .on_warn
+            .as_ref()
+            .and_then(|cmd| cmd.resolve(power.source() == &PowerSource::Battery))
+    }
+
+    // With `lock`, the listener's own lock command runs instead of the global one.
+    fn lock_cmd<'a>(&'a self, general: &'a MoxidleConfig) -> Option<&'a config::Command> {
+        self.config
+            .lock
+            .then(|| self.config.lock_cmd.as_ref().or(general.lock_cmd.as_ref()))
+            .flatten()
+    }
+
+    fn unlock_cmd<'a>(&'a self, general: &'a MoxidleConfig) -> Option<&'a config::Command> {
+        self.config
+            .lock
+            .then(|| {
+                self.config
+                    .unlock_cmd
+                    .as_ref()
+                    .or(general.unlock_cmd.as_ref())
+            })
+            .flatten()
     }
 }
 
 #[derive(Default)]
 struct Inhibitors {
-    #[cfg(feature = "audio")]
+    #[cfg(any(feature = "audio", feature = "pipewire"))]
     audio_inhibitor: bool,
     dbus_inhibitor: bool,
     systemd_inhibitor: bool,
+    control_inhibitor: bool,
+    fullscreen_inhibitor: bool,
+    #[cfg(feature = "portal")]
+    screencast_inhibitor: bool,
+    // Set by the always_awake presence mode.
+    force_inhibit: bool,
 }
 
 impl Inhibitors {
     fn active(&self) -> bool {
-        let mut active = self.dbus_inhibitor || self.systemd_inhibitor;
-        #[cfg(feature = "audio")]
-        {
-            active |= self.audio_inhibitor;
-        }
+        let active = self.dbus_inhibitor
+            || self.systemd_inhibitor
+            || self.control_inhibitor
+            || self.fullscreen_inhibitor
+            || self.force_inhibit;
+        #[cfg(any(feature = "audio", feature = "pipewire"))]
+        let active = active || self.audio_inhibitor;
+        #[cfg(feature = "portal")]
+        let active = active || self.screencast_inhibitor;
         active
     }
+
+    #[cfg(feature = "metrics")]
+    fn count(&self) -> usize {
+        let count = [
+            self.dbus_inhibitor,
+            self.systemd_inhibitor,
+            self.control_inhibitor,
+            self.fullscreen_inhibitor,
+            self.force_inhibit,
+        ]
+        .into_iter()
+        .filter(|&active| active)
+        .count();
+        #[cfg(any(feature = "audio", feature = "pipewire"))]
+        let count = count + usize::from(self.audio_inhibitor);
+        #[cfg(feature = "portal")]
+        let count = count + usize::from(self.screencast_inhibitor);
+        count
+    }
+}
+
+// Overrides condition-driven behavior, set through the SetPresenceMode D-Bus method.
+#[derive(Serialize, PartialEq, Copy, Clone, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+enum PresenceMode {
+    #[default]
+    Normal,
+    // Inhibits idle like any other inhibitor.
+    AlwaysAwake,
+    // Arms every enabled listener regardless of conditions and inhibitors.
+    ForceIdle,
 }
 
-#[derive(PartialEq, Copy, Clone)]
+#[derive(Serialize, PartialEq, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
 enum LockState {
     Locked,
     Unlocked,
@@ -78,44 +309,149 @@ enum LockState {
 struct State {
     // Notification used to detect when system should resume after being locked externally.
     notification: Option<ext_idle_notification_v1::ExtIdleNotificationV1>,
+    // Zero-timeout notification used to track how long the session has been idle.
+    activity_notification: ext_idle_notification_v1::ExtIdleNotificationV1,
+    idle: bool,
+    last_activity: Instant,
     lock_state: LockState,
+    // Set while the screensaver is active, see Moxidle::update_screensaver_active.
     active_since: Option<Instant>,
-    emit_sender: mpsc::Sender<()>,
+    emit_sender: mpsc::Sender<bool>,
 }
 
 impl State {
-    fn new(emit_sender: mpsc::Sender<()>) -> Self {
+    fn new(
+        emit_sender: mpsc::Sender<bool>,
+        activity_notification: ext_idle_notification_v1::ExtIdleNotificationV1,
+    ) -> Self {
+        // A previous instance may have been restarted while the session was locked.
+        let restored = state_file::load_locked();
+        if restored.is_some() {
+            log::info!("Restored locked state from a previous instance");
+        }
+
         Self {
             notification: None,
-            active_since: None,
-            lock_state: LockState::Unlocked,
+            activity_notification,
+            idle: false,
+            last_activity: Instant::now(),
+            active_since: restored,
+            lock_state: if restored.is_some() {
+                LockState::Locked
+            } else {
+                LockState::Unlocked
+            },
             emit_sender,
         }
     }
 
-    fn set_lock_state(&mut self, lock_state: LockState) {
-        if self.lock_state != lock_state {
-            if let Err(e) = self.emit_sender.send(()) {
-                log::error!("Failed to send emit event: {e}");
-            }
-            self.lock_state = lock_state;
-            if self.lock_state == LockState::Locked {
-                self.active_since = Some(Instant::now());
-            }
+    fn save(&self) {
+        state_file::save(self.lock_state == LockState::Locked, self.active_since);
+    }
+
+    fn session_idle_time(&self) -> u32 {
+        if self.idle {
+            self.last_activity.elapsed().as_secs() as u32
+        } else {
+            0
+        }
+    }
+
+    // Returns whether the lock state changed.
+    fn set_lock_state(&mut self, lock_state: LockState) -> bool {
+        if self.lock_state == lock_state {
+            return false;
         }
+
+        self.lock_state = lock_state;
+        self.save();
+        true
+    }
+
+    fn is_active(&self) -> bool {
+        self.active_since.is_some()
     }
+
+    // Emits ActiveChanged on transitions, returns whether the screensaver state changed.
+    fn set_active(&mut self, active: bool) -> bool {
+        if self.is_active() == active {
+            return false;
+        }
+
+        if let Err(e) = self.emit_sender.send(active) {
+            log::error!("Failed to send emit event: {e}");
+        }
+        self.active_since = active.then(Instant::now);
+        self.save();
+        true
+    }
+}
+
+const POWER_SETTLE: Duration = Duration::from_millis(50);
+
+// Binds the first idle notifier the compositor advertises, along with the name of its global.
+fn bind_notifier(
+    registry: &wl_registry::WlRegistry,
+    contents: &GlobalListContents,
+    qh: &QueueHandle<Moxidle>,
+) -> Option<(u32, ext_idle_notifier_v1::ExtIdleNotifierV1)> {
+    contents.with_list(|list| {
+        list.iter()
+            .find(|global| {
+                global.interface == ext_idle_notifier_v1::ExtIdleNotifierV1::interface().name
+            })
+            .map(|global| {
+                (
+                    global.name,
+                    registry.bind(global.name, global.version.min(2), qh, ()),
+                )
+            })
+    })
 }
 
 struct Moxidle {
     state: State,
-    seat: wl_seat::WlSeat,
+    // The first seat is used for session-wide tracking, the rest only with seat_mode.
+    seats: Vec<wl_seat::WlSeat>,
     notifier: ext_idle_notifier_v1::ExtIdleNotifierV1,
+    // Name of the notifier's global, to notice when the compositor removes it.
+    notifier_name: u32,
+    // Set while the compositor advertises no notifier, nothing can be armed then.
+    notifier_lost: bool,
     listeners: Vec<TimeoutHandler>,
     config: MoxidleConfig,
     inhibitors: Inhibitors,
+    presence_mode: PresenceMode,
+    // Set once the UPower backend found no UPower service.
+    upower_unavailable: bool,
+    // Listener that fired first in the current idle period, with listener_priority = "first".
+    idle_owner: Option<usize>,
+    inhibited: bool,
+    control_sender: tokio_mpsc::UnboundedSender<control::Signal>,
     qh: QueueHandle<Self>,
+    loop_handle: LoopHandle<'static, Self>,
+    commands: CommandRunner,
     power: Power,
+    outputs: output::Outputs,
+    toplevels: toplevel::Toplevels,
+    lid_closed: Option<bool>,
+    // Whether the logind session is in the foreground, assumed until logind says otherwise.
+    session_active: bool,
+    // Coalesces bursts of UPower updates, see `power_changed`.
+    power_settle_timer: Option<RegistrationToken>,
+    power_dirty: bool,
+    #[cfg(feature = "network")]
+    network: network::NetworkState,
+    #[cfg(feature = "portal")]
+    screencasting: bool,
     usb_context: Option<rusb::Context>,
+    // Only tracked while hotplug events are delivered.
+    usb_devices: Option<usb::DeviceCache>,
+    event_clients: event_socket::Clients,
+    jitter: jitter::Jitter,
+    // Where the config was loaded from, for reloads.
+    config_path: Option<PathBuf>,
+    allow_empty: bool,
 }
 
 impl Deref for Moxidle {
@@ -130,34 +466,131 @@ impl Moxidle {
     fn new(
         globals: GlobalList,
         qh: QueueHandle<Self>,
-        config_path: Option<PathBuf>,
-        emit_sender: mpsc::Sender<()>,
-        usb_context: Option<rusb::Context>,
+        loop_handle: LoopHandle<'static, Self>,
+        general_config: MoxidleConfig,
+        listener_configs: Vec<ListenerConfig>,
+        emit_sender: mpsc::Sender<bool>,
+        control_sender: tokio_mpsc::UnboundedSender<control::Signal>,
     ) -> anyhow::Result<Self> {
-        let notifier = globals
-            .bind(&qh, 1..=1, ())
+        let (notifier_name, notifier) = bind_notifier(globals.registry(), globals.contents(), &qh)
             .expect("Compositor doesn't support ext-idle-notifier-v1");
 
-        let seat = globals.bind::<wl_seat::WlSeat, _, _>(&qh, 1..=4, ())?;
-        seat.get_pointer(&qh, ());
+        if notifier.version() < 2
+            && listener_configs
+                .iter()
+                .any(|listener| listener.ignores_inhibitors())
+        {
+            log::warn!(
+                "Compositor only supports ext-idle-notifier-v1 version {}, listeners ignoring inhibitors will still respect Wayland idle inhibitors",
+                notifier.version()
+            );
+        }
+
+        let seats = if general_config.seat_mode.is_some() {
+            globals.contents().with_list(|list| {
+                list.iter()
+                    .filter(|global| global.interface == wl_seat::WlSeat::interface().name)
+                    .map(|global| {
+                        globals.registry().bind::<wl_seat::WlSeat, _, _>(
+                            global.name,
+                            global.version.min(4),
+                            &qh,
+                            (),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+        } else {
+            vec![globals.bind::<wl_seat::WlSeat, _, _>(&qh, 1..=4, ())?]
+        };
+        let Some(seat) = seats.first() else {
+            return Err(anyhow::anyhow!("Compositor doesn't advertise any wl_seat"));
+        };
+        log::info!("Tracking idle state of {} seat(s)", seats.len());
+        seats.iter().for_each(|seat| {
+            seat.get_pointer(&qh, ());
+        });
+
+        let activity_notification = notifier.get_idle_notification(0, seat, &qh, ());
+
+        // zwp_idle_inhibit_manager_v1 has no events, so inhibitors created by other clients can't
+        // be observed. Compositors apply them before sending idle notifications instead, which
+        // holds back every listener while a surface inhibits idle.
+        if !globals.contents().with_list(|list| {
+            list.iter()
+                .any(|global| global.interface == "zwp_idle_inhibit_manager_v1")
+        }) {
+            log::info!(
+                "Compositor doesn't support idle-inhibit-unstable-v1, applications can't inhibit idle through Wayland"
+            );
+        }
+
+        if let Err(e) = globals.bind::<ZwlrOutputManagerV1, _, _>(&qh, 1..=4, ()) {
+            log::info!(
+                "Compositor doesn't support wlr-output-management-unstable-v1, output conditions will never be met: {e}"
+            );
+        }
+
+        if general_config.inhibit_on_fullscreen
+            && let Err(e) = globals.bind::<ZwlrForeignToplevelManagerV1, _, _>(&qh, 1..=3, ())
+        {
+            log::warn!(
+                "Compositor doesn't support wlr-foreign-toplevel-management-unstable-v1, fullscreen windows won't inhibit idle: {e}"
+            );
+        }
 
-        let (general_config, listener_configs) = Config::load(config_path)?;
+        log::info!(
+            "{} of {} listener(s) enabled",
+            listener_configs
+                .iter()
+                .filter(|listener| listener.is_enabled())
+                .count(),
+            listener_configs.len()
+        );
 
         let listeners = listener_configs
             .into_iter()
             .map(TimeoutHandler::new)
             .collect();
+        let usb_context = general_config
+            .backend_enabled(Backend::Usb)
+            .then(rusb::Context::new)
+            .and_then(Result::ok);
 
         Ok(Self {
             usb_context,
-            state: State::new(emit_sender),
+            usb_devices: None,
+            event_clients: event_socket::Clients::default(),
+            jitter: jitter::Jitter::default(),
+            config_path: None,
+            allow_empty: false,
+            state: State::new(emit_sender, activity_notification),
             power: Power::default(),
+            outputs: output::Outputs::default(),
+            toplevels: toplevel::Toplevels::default(),
+            lid_closed: None,
+            session_active: true,
+            power_settle_timer: None,
+            power_dirty: false,
+            #[cfg(feature = "network")]
+            network: network::NetworkState::default(),
+            #[cfg(feature = "portal")]
+            screencasting: false,
             listeners,
+            commands: CommandRunner::new(tokio::runtime::Handle::current(), &general_config),
             config: general_config,
             notifier,
-            seat,
+            notifier_name,
+            notifier_lost: false,
+            seats,
             inhibitors: Inhibitors::default(),
+            presence_mode: PresenceMode::default(),
+            idle_owner: None,
+            upower_unavailable: false,
+            inhibited: false,
+            control_sender,
             qh,
+            loop_handle,
         })
     }
 
@@ -165,19 +598,40 @@ impl Moxidle {
     where
         F: Fn(&Condition) -> bool,
     {
-        !self
-            .listeners
-            .iter()
-            .any(|timeout| timeout.config.conditions.iter().any(&condition_predicate))
+        !self.listeners.iter().any(|timeout| {
+            timeout.config.is_enabled()
+                && timeout
+                    .config
+                    .conditions
+                    .iter()
+                    .any(|condition| condition.contains(&condition_predicate))
+        })
     }
 
     fn handle_app_event(&mut self, event: Event) {
+        self.event_clients.broadcast(&event);
+
         match event {
             Event::GetLockState(sender) => {
                 if sender.send(self.state.lock_state).is_err() {
                     log::error!("Failed to send lock state");
                 }
             }
+            Event::GetScreenSaverActive(sender) => {
+                if sender.send(self.state.is_active()).is_err() {
+                    log::error!("Failed to send screensaver active state");
+                }
+            }
+            Event::GetSeatIdle(sender) => {
+                if sender.send(self.state.idle).is_err() {
+                    log::error!("Failed to send seat idle state");
+                }
+            }
+            Event::GetSessionIdleTime(sender) => {
+                if sender.send(self.state.session_idle_time()).is_err() {
+                    log::error!("Failed to send session idle time");
+                }
+            }
             Event::GetActiveTime(sender) => {
                 if let Some(time) = self.state.active_since {
                     if sender.send(time.elapsed().as_secs() as u32).is_err() {
@@ -189,27 +643,167 @@ impl Moxidle {
             }
             Event::BatteryState(state) => {
                 self.power.update_state(state);
-                self.reset_idle_timers();
+                self.power_changed();
             }
             Event::BatteryLevel(level) => {
                 self.power.update_level(level);
-                self.reset_idle_timers();
+                self.power_changed();
+            }
+            Event::BatteryTimeToEmpty(time_to_empty) => {
+                self.power.update_time_to_empty(time_to_empty);
+                self.power_changed();
             }
             Event::OnBattery(on_battery) => {
                 self.power.update_source(on_battery);
-                self.reset_idle_timers();
+                self.power_changed();
+            }
+            Event::ChargerOnline(online) => {
+                self.power.update_charger_online(online);
+                self.power_changed();
+            }
+            Event::GetBatteryPercentage(sender) => {
+                if sender.send(self.power.percentage()).is_err() {
+                    log::error!("Failed to send battery percentage");
+                }
+            }
+            Event::GetListeners(sender) => {
+                let inhibited = self.inhibited;
+                let fired = self.fired_listeners();
+                let listeners = self
+                    .listeners
+                    .iter()
+                    .map(|handler| {
+                        let met = self.should_arm(handler, inhibited, &fired);
+                        (
+                            handler.config.timeout_millis() / 1000,
+                            handler.is_armed(),
+                            met,
+                            handler
+                                .on_timeout(&self.power)
+                                .map(|cmd| cmd.to_string())
+                                .unwrap_or_default(),
+                        )
+                    })
+                    .collect();
+                if sender.send(listeners).is_err() {
+                    log::error!("Failed to send listeners");
+                }
             }
             Event::BatteryPercentage(battery) => {
-                self.power.update_percentage(battery);
+                let previous = self.power.update_percentage(battery);
+                if previous != Some(self.power.percentage())
+                    && let Err(e) = self
+                        .control_sender
+                        .send(control::Signal::BatteryPercentageChanged)
+                {
+                    log::error!("Failed to send BatteryPercentageChanged signal: {e}");
+                }
+                if let Some(previous) = previous {
+                    self.run_battery_threshold_commands(previous);
+                }
+                let percentage = self.power.percentage();
+                self.listeners
+                    .iter_mut()
+                    .for_each(|handler| handler.update_battery_thresholds(percentage));
+                self.power_changed();
+            }
+            Event::Reload(sender) => {
+                let result = self.reload();
+                if let Err(e) = result.as_ref() {
+                    log::error!("Failed to reload config, keeping the current one: {e}");
+                }
+                if sender.send(result).is_err() {
+                    log::error!("Failed to send reload result");
+                }
+            }
+            Event::SimulateUserActivity => {
                 self.reset_idle_timers();
             }
-            Event::SimulateUserActivity | Event::Usb => {
+            Event::Usb(device_event) => {
+                log::info!("USB device event: {device_event}");
+                if let Some(usb_devices) = self.usb_devices.as_mut() {
+                    usb_devices.update(&device_event);
+                }
                 self.reset_idle_timers();
             }
             Event::ScreenSaverInhibit(inhibited) => {
                 self.inhibitors.dbus_inhibitor = inhibited;
                 self.reset_idle_timers();
             }
+            Event::ScreenSaverThrottle(throttled) => {
+                let cmd = if throttled {
+                    self.on_throttle.as_ref()
+                } else {
+                    self.on_unthrottle.as_ref()
+                };
+
+                if let Some(cmd) = cmd {
+                    let cmd = cmd.clone();
+                    let event = if throttled { "throttle" } else { "unthrottle" };
+                    self.commands
+                        .run(cmd, &CommandContext::new(event, &self.power));
+                }
+            }
+            Event::LidClosed(closed) => {
+                // The initial state is only recorded, commands run on changes.
+                let Some(previous) = self.lid_closed.replace(closed) else {
+                    self.reset_idle_timers();
+                    return;
+                };
+                if previous == closed {
+                    return;
+                }
+
+                let cmd = if closed {
+                    self.on_lid_close.as_ref()
+                } else {
+                    self.on_lid_open.as_ref()
+                };
+
+                if let Some(cmd) = cmd {
+                    let cmd = cmd.clone();
+                    let event = if closed { "lid_close" } else { "lid_open" };
+                    self.commands
+                        .run(cmd, &CommandContext::new(event, &self.power));
+                }
+                self.reset_idle_timers();
+            }
+            Event::ControlInhibit(inhibited) => {
+                self.inhibitors.control_inhibitor = inhibited;
+                self.reset_idle_timers();
+            }
+            Event::UPowerUnavailable => {
+                self.upower_unavailable = true;
+                let outcome = match self.missing_upower {
+                    MissingService::Unmet => "are never met",
+                    MissingService::Ignore => "are ignored",
+                };
+                log::warn!("UPower isn't available, battery and power source conditions {outcome}");
+                self.listeners
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, handler)| {
+                        handler.config.is_enabled()
+                            && handler
+                                .config
+                                .conditions
+                                .iter()
+                                .any(Condition::needs_upower)
+                    })
+                    .for_each(|(index, handler)| match handler.config.name.as_deref() {
+                        Some(name) => {
+                            log::warn!("Listener {} ({name}) depends on UPower", index + 1)
+                        }
+                        None => log::warn!("Listener {} depends on UPower", index + 1),
+                    });
+                self.reset_idle_timers();
+            }
+            Event::SetPresenceMode(mode) => {
+                log::info!("Presence mode set to {mode:?}");
+                self.presence_mode = mode;
+                self.inhibitors.force_inhibit = mode == PresenceMode::AlwaysAwake;
+                self.reset_idle_timers();
+            }
             Event::BlockInhibited(inhibited) => {
                 log::info!("{inhibited}");
                 if inhibited != self.inhibitors.systemd_inhibitor {
@@ -220,11 +814,26 @@ impl Moxidle {
                     self.reset_idle_timers();
                 }
             }
-            #[cfg(feature = "audio")]
+            #[cfg(any(feature = "audio", feature = "pipewire"))]
             Event::AudioInhibit(inhibited) => {
                 self.inhibitors.audio_inhibitor = inhibited;
                 self.reset_idle_timers();
             }
+            #[cfg(feature = "network")]
+            Event::NetworkState { connected, ssid } => {
+                log::info!("Network connected: {connected}, SSID: {ssid:?}");
+                self.network = network::NetworkState { connected, ssid };
+                self.reset_idle_timers();
+            }
+            #[cfg(feature = "portal")]
+            Event::ScreenCastActive(active) => {
+                self.screencasting = active;
+                self.inhibitors.screencast_inhibitor = active && self.inhibit_on_screencast;
+                self.reset_idle_timers();
+            }
+            Event::SessionLocked(true) if self.session_in_background() => {
+                log::info!("Session is in the background, ignoring lock request");
+            }
             Event::SessionLocked(locked) => {
                 let cmd = if locked {
                     self.lock_cmd.as_ref()
@@ -234,37 +843,33 @@ impl Moxidle {
 
                 if let Some(cmd) = cmd {
                     let cmd = cmd.clone();
-                    execute_command(cmd);
+                    let event = if locked { "lock" } else { "unlock" };
+                    self.commands
+                        .run(cmd, &CommandContext::new(event, &self.power));
                 }
 
                 if locked {
-                    self.state.set_lock_state(LockState::Locked);
-                    if self.state.notification.is_none() {
-                        self.state.notification =
-                            Some(
-                                self.notifier
-                                    .get_idle_notification(0, &self.seat, &self.qh, ()),
-                            );
-                    }
+                    self.set_lock_state(LockState::Locked);
+                    self.arm_resume_probe();
                 } else {
-                    self.state.set_lock_state(LockState::Unlocked);
+                    self.set_lock_state(LockState::Unlocked);
                     if let Some(notification) = self.state.notification.take() {
                         notification.destroy();
                     }
                 }
             }
+            Event::SessionActive(active) => {
+                log::info!("Session active: {active}");
+                self.session_active = active;
+                self.reset_idle_timers();
+            }
             Event::ScreenSaverLock => {
                 if let Some(lock_cmd) = self.lock_cmd.as_ref() {
                     let lock_cmd = lock_cmd.clone();
-                    execute_command(lock_cmd);
-                    self.state.set_lock_state(LockState::Locked);
-                    if self.state.notification.is_none() {
-                        self.state.notification =
-                            Some(
-                                self.notifier
-                                    .get_idle_notification(0, &self.seat, &self.qh, ()),
-                            );
-                    }
+                    self.commands
+                        .run(lock_cmd, &CommandContext::new("lock", &self.power));
+                    self.set_lock_state(LockState::Locked);
+                    self.arm_resume_probe();
                 }
             }
             Event::PrepareForSleep(sleep) => {
@@ -276,131 +881,583 @@ impl Moxidle {
 
                 if let Some(cmd) = cmd {
                     let cmd = cmd.clone();
-                    execute_command(cmd);
+                    let event = if sleep { "before_sleep" } else { "after_sleep" };
+                    self.commands
+                        .run(cmd, &CommandContext::new(event, &self.power));
                 }
             }
         }
     }
 
+    // Runs the commands of every threshold crossed since the `previous` percentage.
+    fn run_battery_threshold_commands(&self, previous: f64) {
+        let current = self.power.percentage();
+
+        self.on_battery_below
+            .iter()
+            .filter(|(threshold, _)| {
+                let threshold = f64::from(**threshold);
+                previous >= threshold && current < threshold
+            })
+            .for_each(|(threshold, cmd)| {
+                log::info!("Battery dropped below {threshold}%, executing command: {cmd}");
+                self.commands.run(
+                    cmd.clone(),
+                    &CommandContext::new("battery_below", &self.power),
+                );
+            });
+
+        self.on_battery_above
+            .iter()
+            .filter(|(threshold, _)| {
+                let threshold = f64::from(**threshold);
+                previous <= threshold && current > threshold
+            })
+            .for_each(|(threshold, cmd)| {
+                log::info!("Battery rose above {threshold}%, executing command: {cmd}");
+                self.commands.run(
+                    cmd.clone(),
+                    &CommandContext::new("battery_above", &self.power),
+                );
+            });
+    }
+
+    fn run_timeout(&mut self, index: usize) {
+        let Some(handler) = self.listeners.get_mut(index) else {
+            return;
+        };
+
+        handler.idled = true;
+        handler.last_fired = Some(Instant::now());
+        handler.fired_count += 1;
+        if let Some(cmd) = handler.lock_cmd(&self.config) {
+            log::info!("Executing lock command: {cmd}");
+            let context =
+                CommandContext::new("lock", &self.power).listener(&handler.config, handler.timeout);
+            self.commands.run(cmd.clone(), &context);
+        }
+        if let Some(cmd) = handler.on_timeout(&self.power) {
+            log::info!("Executing timeout command: {cmd}");
+            let context = CommandContext::new("timeout", &self.power)
+                .listener(&handler.config, handler.timeout);
+            self.commands.run(cmd.clone(), &context);
+        }
+        if handler.config.sets_lock_state() {
+            self.set_lock_state(LockState::Locked);
+        } else {
+            self.update_screensaver_active();
+        }
+        self.update_fired_listeners();
+    }
+
+    fn run_warning(&mut self, index: usize) {
+        let Some(handler) = self.listeners.get(index) else {
+            return;
+        };
+
+        if let Some(cmd) = handler.on_warn(&self.power) {
+            log::info!("Executing warning command: {cmd}");
+            let context =
+                CommandContext::new("warn", &self.power).listener(&handler.config, handler.timeout);
+            self.commands.run(cmd.clone(), &context);
+        }
+    }
+
+    // UPower tends to send several property changes at once. The first change applies right away,
+    // the rest of the burst is applied together once it settles.
+    fn power_changed(&mut self) {
+        if self.power_settle_timer.is_some() {
+            self.power_dirty = true;
+            return;
+        }
+
+        self.reset_idle_timers();
+        let timer = calloop::timer::Timer::from_duration(POWER_SETTLE);
+        let result = self.loop_handle.insert_source(timer, |_, _, state| {
+            state.power_settle_timer = None;
+            if std::mem::take(&mut state.power_dirty) {
+                state.reset_idle_timers();
+            }
+            calloop::timer::TimeoutAction::Drop
+        });
+
+        match result {
+            Ok(token) => self.power_settle_timer = Some(token),
+            Err(e) => log::warn!("Failed to start power settle timer: {e}"),
+        }
+    }
+
+    // Notifications of a removed notifier never fire again, so every listener silently stops
+    // working. The notifier is re-bound right away if the compositor advertises another one,
+    // otherwise once it does.
+    fn handle_notifier_removed(
+        &mut self,
+        registry: &wl_registry::WlRegistry,
+        contents: &GlobalListContents,
+    ) {
+        log::error!("The compositor removed ext-idle-notifier-v1, listeners stopped working");
+        self.listeners.iter_mut().for_each(TimeoutHandler::disarm);
+        if self.rebind_notifier(registry, contents) {
+            return;
+        }
+
+        self.notifier_lost = true;
+        if let Some(cmd) = self.on_compositor_lost.clone() {
+            log::info!("Executing compositor lost command: {cmd}");
+            self.commands
+                .run(cmd, &CommandContext::new("compositor_lost", &self.power));
+        }
+    }
+
+    // Replaces the notifier and recreates the notifications made from it, returns false if the
+    // compositor doesn't advertise one.
+    fn rebind_notifier(
+        &mut self,
+        registry: &wl_registry::WlRegistry,
+        contents: &GlobalListContents,
+    ) -> bool {
+        let Some((name, notifier)) = bind_notifier(registry, contents, &self.qh) else {
+            return false;
+        };
+        log::info!("Bound a new ext-idle-notifier-v1, re-arming listeners");
+
+        self.notifier.destroy();
+        self.notifier = notifier;
+        self.notifier_name = name;
+        self.notifier_lost = false;
+
+        self.state.activity_notification.destroy();
+        self.state.activity_notification =
+            self.notifier
+                .get_idle_notification(0, &self.seats[0], &self.qh, ());
+        if let Some(notification) = self.state.notification.take() {
+            notification.destroy();
+            self.arm_resume_probe();
+        }
+
+        self.reset_idle_timers();
+        true
+    }
+
+    // Defers the timeout command so that activity within `grace_ms` can cancel it.
+    fn start_grace_period(&mut self, index: usize, grace_ms: u32) {
+        let timer = calloop::timer::Timer::from_duration(Duration::from_millis(grace_ms.into()));
+        let result = self.loop_handle.insert_source(timer, move |_, _, state| {
+            if let Some(handler) = state.listeners.get_mut(index) {
+                handler.grace_timer = None;
+            }
+            state.run_timeout(index);
+            calloop::timer::TimeoutAction::Drop
+        });
+
+        match result {
+            Ok(token) => self.listeners[index].grace_timer = Some(token),
+            Err(e) => {
+                log::error!("Failed to start grace period timer: {e}");
+                self.run_timeout(index);
+            }
+        }
+    }
+
+    // Re-reads the config file and replaces the listeners and general settings, the current
+    // config is kept if it fails to load. Backends keep the settings they were started with.
+    fn reload(&mut self) -> anyhow::Result<()> {
+        let (config, listener_configs) = Config::load(self.config_path.clone(), self.allow_empty)?;
+        let previous = self.listeners.len();
+
+        for handler in self.listeners.iter_mut() {
+            handler.disarm();
+            if let Some(token) = handler.grace_timer.take() {
+                self.loop_handle.remove(token);
+            }
+        }
+        self.listeners = listener_configs
+            .into_iter()
+            .map(TimeoutHandler::new)
+            .collect();
+        self.idle_owner = None;
+        self.commands.reconfigure(&config);
+        self.config = config;
+
+        log::info!(
+            "Config reloaded, listeners: {previous} -> {}",
+            self.listeners.len()
+        );
+        self.reset_idle_timers();
+        self.update_screensaver_active();
+        Ok(())
+    }
+
+    // Creates the zero-timeout notification that unlocks the session on the next activity after
+    // it was locked externally.
+    fn arm_resume_probe(&mut self) {
+        if self.detect_external_resume() && self.state.notification.is_none() {
+            self.state.notification =
+                Some(
+                    self.notifier
+                        .get_idle_notification(0, &self.seats[0], &self.qh, ()),
+                );
+        }
+    }
+
+    // Listeners are re-armed on lock transitions for session_locked and session_unlocked conditions.
+    fn set_lock_state(&mut self, lock_state: LockState) {
+        if self.state.set_lock_state(lock_state) {
+            self.event_clients
+                .broadcast(&StateChange::LockState(lock_state));
+            self.reset_idle_timers();
+        }
+        self.update_screensaver_active();
+    }
+
+    // The screensaver is active while the session is locked or while a listener with an
+    // on_timeout command has fired, so blanking the screen counts even without a lock.
+    fn update_screensaver_active(&mut self) {
+        let active = self.state.lock_state == LockState::Locked
+            || self
+                .listeners
+                .iter()
+                .any(|handler| handler.idled && handler.config.on_timeout.is_some());
+        if self.state.set_active(active) {
+            self.event_clients
+                .broadcast(&StateChange::ScreenSaverActive(active));
+        }
+    }
+
+    fn update_inhibited(&mut self) -> bool {
+        let inhibited = self.inhibitors.active();
+        if inhibited != self.inhibited {
+            self.inhibited = inhibited;
+            if let Err(e) = self
+                .control_sender
+                .send(control::Signal::InhibitedChanged(inhibited))
+            {
+                log::error!("Failed to send InhibitedChanged signal: {e}");
+            }
+        }
+        inhibited
+    }
+
+    // Reverts the side effects of idling before exiting, returns handles to the spawned commands.
+    fn shutdown(&mut self) -> Vec<tokio::task::JoinHandle<()>> {
+        let mut commands = Vec::new();
+
+        // Listeners with `lock` run their own unlock command below.
+        let listener_locked = self
+            .listeners
+            .iter()
+            .any(|handler| handler.idled && handler.config.lock);
+        if self.state.lock_state == LockState::Locked
+            && !listener_locked
+            && let Some(cmd) = self.unlock_cmd.as_ref()
+        {
+            log::info!("Executing unlock command: {cmd}");
+            commands.extend(
+                self.commands
+                    .run(cmd.clone(), &CommandContext::new("unlock", &self.power)),
+            );
+            // The session is unlocked on the way out, the next instance must not restore it.
+            state_file::clear();
+        }
+
+        self.listeners
+            .iter()
+            .filter(|handler| handler.idled)
+            .for_each(|handler| {
+                if let Some(cmd) = handler.unlock_cmd(&self.config) {
+                    log::info!("Executing unlock command: {cmd}");
+                    let context = CommandContext::new("unlock", &self.power)
+                        .listener(&handler.config, handler.timeout);
+                    commands.extend(self.commands.run(cmd.clone(), &context));
+                    state_file::clear();
+                }
+                if let Some(cmd) = handler.on_resume(&self.power) {
+                    log::info!("Executing resume command: {cmd}");
+                    let context = CommandContext::new("resume", &self.power)
+                        .listener(&handler.config, handler.timeout);
+                    commands.extend(self.commands.run(cmd.clone(), &context));
+                }
+            });
+
+        commands
+    }
+
+    // Names of the listeners whose timeout fired and that haven't resumed since.
+    fn fired_listeners(&self) -> Vec<Arc<str>> {
+        self.listeners
+            .iter()
+            .filter(|handler| handler.idled)
+            .filter_map(|handler| handler.config.name.clone())
+            .collect()
+    }
+
+    // Whether the listener's conditions are met, regardless of inhibitors.
+    fn conditions_met(&self, handler: &TimeoutHandler, fired: &[Arc<str>]) -> bool {
+        let idled = handler.idled;
+        let skip_upower = self.upower_unavailable && self.missing_upower == MissingService::Ignore;
+        let skip = |condition: &Condition| skip_upower && condition.needs_upower();
+        let config = &handler.config;
+        config.conditions_met(skip, |condition| match condition {
+            Condition::OnBattery => self.power.source() == &PowerSource::Battery,
+            Condition::OnAc => self.power.source() == &PowerSource::Plugged,
+            Condition::ChargerOnline => self.power.charger_online(),
+            Condition::Charging => self.power.state() == &BatteryState::Charging,
+            Condition::Discharging => self.power.state() == &BatteryState::Discharging,
+            Condition::FullyCharged => self.power.state() == &BatteryState::FullyCharged,
+            Condition::BatteryBelow(battery) => {
+                battery.below(self.power.percentage(), handler.below_met.contains(battery))
+            }
+            Condition::BatteryAbove(battery) => {
+                battery.above(self.power.percentage(), handler.above_met.contains(battery))
+            }
+            Condition::BatteryEqual(battery) => {
+                self.power.level_cmp(battery) == LevelComparison::Equal
+            }
+            Condition::BatteryLevel(level) => self.power.level() == level,
+            Condition::BatteryState(state) => self.power.state() == state,
+            Condition::TimeToEmptyBelow(secs) => self
+                .power
+                .time_to_empty()
+                .is_some_and(|time_to_empty| time_to_empty < i64::from(*secs)),
+            Condition::UsbPlugged(matcher) => {
+                usb::is_plugged(
+                    matcher,
+                    self.usb_devices.as_ref(),
+                    self.usb_context.as_ref(),
+                ) == Some(true)
+            }
+            Condition::UsbUnplugged(matcher) => {
+                usb::is_plugged(
+                    matcher,
+                    self.usb_devices.as_ref(),
+                    self.usb_context.as_ref(),
+                ) == Some(false)
+            }
+            Condition::UsbCountAtLeast(count) => {
+                usb::device_count(self.usb_devices.as_ref(), self.usb_context.as_ref())
+                    .is_some_and(|devices| devices >= *count as usize)
+            }
+            Condition::UsbClassPresent(class) => {
+                let matcher = usb::UsbMatcher {
+                    class: usb::class_code(class),
+                    ..Default::default()
+                };
+                matcher.class.is_some()
+                    && usb::is_plugged(
+                        &matcher,
+                        self.usb_devices.as_ref(),
+                        self.usb_context.as_ref(),
+                    ) == Some(true)
+            }
+            Condition::InputDevicePresent(name) => input::is_present(name),
+            Condition::ProcessRunning(name) => process::is_running(name),
+            Condition::CpuLoadAbove(threshold) => cpu::load().is_some_and(|load| load > *threshold),
+            Condition::TempAbove { sensor, celsius } => {
+                thermal::temperature(sensor).is_some_and(|temperature| temperature > *celsius)
+            }
+            Condition::OutputConnected(name) => self.outputs.is_connected(name),
+            Condition::MultipleOutputs => self.outputs.enabled_count() > 1,
+            Condition::LidClosed => self.lid_closed == Some(true),
+            Condition::SessionLocked => self.state.lock_state == LockState::Locked,
+            Condition::SessionActive => self.session_active,
+            Condition::SessionUnlocked => self.state.lock_state == LockState::Unlocked,
+            // A fired listener stays armed until it resumes itself, so its on_resume isn't
+            // lost when the listener it depends on resumes first.
+            Condition::ListenerFired(name) => idled || fired.contains(name),
+            #[cfg(feature = "network")]
+            Condition::NetworkConnected => self.network.connected,
+            #[cfg(feature = "network")]
+            Condition::Ssid(ssid) => self.network.ssid.as_ref() == Some(ssid),
+            #[cfg(feature = "portal")]
+            Condition::ScreenCasting => self.screencasting,
+            Condition::Not(_) => {
+                unreachable!("negations are resolved by Condition::evaluate")
+            }
+            Condition::TimeRange { start, end } => {
+                let now = chrono::Local::now();
+                config::time_in_range(start, end, now.hour() * 60 + now.minute())
+            }
+        })
+    }
+
+    // Whether moxidle should stay out of the way of the foreground session.
+    fn session_in_background(&self) -> bool {
+        self.require_active_session && !self.session_active
+    }
+
+    // Whether the listener should have idle notifications right now.
+    fn should_arm(&self, handler: &TimeoutHandler, inhibited: bool, fired: &[Arc<str>]) -> bool {
+        if self.notifier_lost || self.session_in_background() {
+            return false;
+        }
+
+        if self.presence_mode == PresenceMode::ForceIdle {
+            return handler.config.is_enabled();
+        }
+
+        handler.config.is_enabled()
+            && (!inhibited || handler.config.ignores_inhibitors())
+            && self.conditions_met(handler, fired)
+    }
+
     fn reset_idle_timers(&mut self) {
-        self.listeners.iter_mut().for_each(|handler| {
-            let current_met = if !self.inhibitors.active() {
-                handler
-                    .config
-                    .conditions
-                    .iter()
-                    .all(|condition| match condition {
-                        Condition::OnBattery => self.power.source() == &PowerSource::Battery,
-                        Condition::OnAc => self.power.source() == &PowerSource::Plugged,
-                        Condition::BatteryBelow(battery) => {
-                            self.power.level_cmp(battery) == LevelComparison::Below
-                        }
-                        Condition::BatteryAbove(battery) => {
-                            self.power.level_cmp(battery) == LevelComparison::Above
-                        }
-                        Condition::BatteryEqual(battery) => {
-                            self.power.level_cmp(battery) == LevelComparison::Equal
-                        }
-                        Condition::BatteryLevel(level) => self.power.level() == level,
-                        Condition::BatteryState(state) => self.power.state() == state,
-                        Condition::UsbPlugged(id) => {
-                            self.usb_context
-                                .as_ref()
-                                .and_then(|ctx| ctx.devices().ok())
-                                .is_some_and(|devices| {
-                                    devices.iter().any(|device| {
-                                        let desc = device.device_descriptor().unwrap();
-                                        format!("{:04x}:{:04x}", desc.vendor_id(), desc.product_id()) == **id
-                                    })
-                                })
-                        }
-                        Condition::UsbUnplugged(id) => {
-                            self.usb_context
-                                .as_ref()
-                                .and_then(|ctx| ctx.devices().ok())
-                                .is_some_and(|devices| {
-                                    devices.iter().all(|device| {
-                                        let desc = device.device_descriptor().unwrap();
-                                        format!("{:04x}:{:04x}", desc.vendor_id(), desc.product_id()) != **id
-                                    })
-                                })
-                        }
-                    })
-            } else {
-                false
-            };
+        let inhibited = self.update_inhibited();
+        let fired = self.fired_listeners();
+        let met: Vec<_> = self
+            .listeners
+            .iter()
+            .map(|handler| self.should_arm(handler, inhibited, &fired))
+            .collect();
 
+        for (handler, current_met) in self.listeners.iter_mut().zip(met) {
             if current_met {
-                if handler.notification.is_none() {
-                    handler.notification = Some(self.notifier.get_idle_notification(
-                        handler.config.timeout_millis(),
-                        &self.seat,
-                        &self.qh,
-                        (),
-                    ));
+                if !handler.is_armed() {
+                    // Input idle notifications ignore idle inhibitors, they were added in version 2.
+                    let input = handler.config.ignores_inhibitors() && self.notifier.version() >= 2;
+                    let create = |timeout| {
+                        self.seats
+                            .iter()
+                            .map(|seat| {
+                                if input {
+                                    self.notifier.get_input_idle_notification(
+                                        timeout,
+                                        seat,
+                                        &self.qh,
+                                        (),
+                                    )
+                                } else {
+                                    self.notifier
+                                        .get_idle_notification(timeout, seat, &self.qh, ())
+                                }
+                            })
+                            .collect::<Vec<_>>()
+                    };
+
+                    let timeout = match handler.config.timeout_jitter_secs {
+                        Some(jitter_secs) => {
+                            let timeout = self
+                                .jitter
+                                .apply(handler.config.timeout_millis(), jitter_secs);
+                            log::debug!("Effective timeout with jitter: {timeout}ms");
+                            timeout
+                        }
+                        None => handler.config.timeout_millis(),
+                    };
+                    handler.timeout = timeout;
+                    handler.notifications.arm(create(timeout));
+                    if let Some(timeout) = handler.config.warn_timeout_millis_for(timeout) {
+                        handler.warn_notifications.arm(create(timeout));
+                    }
 
                     log::info!(
-                        "Notification created\ntimeout: {}\nconditions: {:?}\non_timeout: {:?}\non_resume: {:?}",
-                        handler.config.timeout,
+                        "Notification created\ntimeout: {}ms\nconditions: {:?}\non_timeout: {:?}\non_resume: {:?}",
+                        timeout,
                         handler.config.conditions,
                         handler.config.on_timeout,
                         handler.config.on_resume
                     );
                 }
-            } else if let Some(notification) = handler.notification.take() {
-                notification.destroy();
+            } else if handler.is_armed() {
+                handler.disarm();
+                handler.idled = false;
+                handler.suppressed = false;
+                if let Some(token) = handler.grace_timer.take() {
+                    self.loop_handle.remove(token);
+                }
                 log::info!(
-                    "Notification destroyed\ntimeout: {}\nconditions: {:?}\non_timeout: {:?}\non_resume: {:?}",
-                    handler.config.timeout,
+                    "Notification destroyed\ntimeout: {}ms\nconditions: {:?}\non_timeout: {:?}\non_resume: {:?}",
+                    handler.config.timeout_millis(),
                     handler.config.conditions,
                     handler.config.on_timeout,
                     handler.config.on_resume
                 );
             }
-        });
+        }
+
+        // A destroyed notification never resumes, so its listener can't keep owning the period.
+        if self
+            .idle_owner
+            .is_some_and(|owner| !self.listeners[owner].is_armed())
+        {
+            self.idle_owner = None;
+        }
+
+        // Destroying notifications un-fires listeners others may depend on. That only ever
+        // shrinks the set, so this settles.
+        if self.fired_listeners() != fired {
+            self.reset_idle_timers();
+        }
+    }
+
+    // Re-evaluates listener_fired conditions after a listener fired or resumed.
+    fn update_fired_listeners(&mut self) {
+        if !self.should_ignore(|c| matches!(c, Condition::ListenerFired(_))) {
+            self.reset_idle_timers();
+        }
     }
 }
 
+// Serialized for the event socket, queries are skipped.
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case", tag = "event", content = "value")]
 enum Event {
+    #[serde(skip)]
     GetActiveTime(oneshot::Sender<u32>),
+    #[serde(skip)]
+    GetSessionIdleTime(oneshot::Sender<u32>),
+    #[serde(skip)]
+    GetScreenSaverActive(oneshot::Sender<bool>),
+    #[serde(skip)]
+    GetSeatIdle(oneshot::Sender<bool>),
+    #[serde(skip)]
     GetLockState(oneshot::Sender<LockState>),
+    #[serde(skip)]
+    GetBatteryPercentage(oneshot::Sender<f64>),
+    #[serde(skip)]
+    GetListeners(oneshot::Sender<Vec<(u32, bool, bool, String)>>),
+    #[serde(skip)]
+    Reload(oneshot::Sender<anyhow::Result<()>>),
     BatteryState(BatteryState),
     BatteryLevel(BatteryLevel),
     OnBattery(bool),
+    ChargerOnline(bool),
     BatteryPercentage(f64),
+    BatteryTimeToEmpty(i64),
     ScreenSaverInhibit(bool),
+    ScreenSaverThrottle(bool),
+    ControlInhibit(bool),
+    UPowerUnavailable,
+    SetPresenceMode(PresenceMode),
     SimulateUserActivity,
     SessionLocked(bool),
+    SessionActive(bool),
     ScreenSaverLock,
     BlockInhibited(bool),
     PrepareForSleep(bool),
-    Usb,
-    #[cfg(feature = "audio")]
+    LidClosed(bool),
+    Usb(usb::DeviceEvent),
+    #[cfg(any(feature = "audio", feature = "pipewire"))]
     AudioInhibit(bool),
+    #[cfg(feature = "network")]
+    NetworkState {
+        connected: bool,
+        ssid: Option<Arc<str>>,
+    },
+    #[cfg(feature = "portal")]
+    ScreenCastActive(bool),
 }
 
-fn execute_command(command: Arc<str>) {
-    let mut child = match Command::new("/bin/sh")
-        .arg("-c")
-        .arg(command.as_ref())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
-    {
-        Ok(child) => child,
-        Err(err) => {
-            log::error!("failed to execute command '{command}': {err}");
-            return;
-        }
-    };
+// Changes made by moxidle itself rather than reported to it, broadcast on the event socket
+// along with the events.
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case", tag = "event", content = "value")]
+enum StateChange {
+    LockState(LockState),
+    ScreenSaverActive(bool),
+}
 
-    std::thread::spawn(move || match child.wait() {
-        Ok(status) if status.success() => {}
-        Ok(status) => {
-            log::error!("command '{command}' failed with exit status {status}")
-        }
-        Err(err) => log::error!("failed to wait on command '{command}': {err}"),
-    });
+fn until_next_minute() -> Duration {
+    Duration::from_secs(60 - u64::from(chrono::Local::now().second()))
 }
 
 impl Dispatch<ext_idle_notification_v1::ExtIdleNotificationV1, ()> for Moxidle {
@@ -412,37 +1469,143 @@ impl Dispatch<ext_idle_notification_v1::ExtIdleNotificationV1, ()> for Moxidle {
         _: &Connection,
         _: &QueueHandle<Self>,
     ) {
+        if &state.state.activity_notification == notification {
+            match event {
+                ext_idle_notification_v1::Event::Idled => state.state.idle = true,
+                ext_idle_notification_v1::Event::Resumed => state.state.idle = false,
+                _ => return,
+            }
+            state.state.last_activity = Instant::now();
+            return;
+        }
+
         // This is for detecting when session is resumed after being locked externally
         if let Some(notification) = state.state.notification.take() {
             if let ext_idle_notification_v1::Event::Resumed = event {
-                state.state.set_lock_state(LockState::Unlocked);
+                state.set_lock_state(LockState::Unlocked);
                 return;
             }
             state.state.notification = Some(notification);
         }
 
-        let Some(handler) = state
-            .listeners
-            .iter()
-            .find(|timeout| timeout.notification.as_ref() == Some(notification))
+        let Some((index, seat, warning)) =
+            state.listeners.iter().enumerate().find_map(|(i, timeout)| {
+                if !timeout.config.is_enabled() {
+                    return None;
+                }
+
+                timeout
+                    .notifications
+                    .seat(notification)
+                    .map(|seat| (i, seat, false))
+                    .or_else(|| {
+                        timeout
+                            .warn_notifications
+                            .seat(notification)
+                            .map(|seat| (i, seat, true))
+                    })
+            })
         else {
             return;
         };
 
+        // Seats are combined according to seat_mode, the listener only idles or resumes when
+        // the combined state changes.
+        let seat_mode = state.seat_mode;
+        let idle = matches!(event, ext_idle_notification_v1::Event::Idled);
+        let handler = &mut state.listeners[index];
+        let notifications = if warning {
+            &mut handler.warn_notifications
+        } else {
+            &mut handler.notifications
+        };
+        if !notifications.update(seat, idle, seat_mode) {
+            return;
+        }
+
+        // Activity after the warning keeps the main timeout from firing, nothing to undo.
+        if warning {
+            if idle {
+                state.run_warning(index);
+            }
+            return;
+        }
+
         match event {
             ext_idle_notification_v1::Event::Idled => {
-                if let Some(cmd) = handler.on_timeout() {
-                    log::info!("Executing timeout command: {cmd}");
-                    execute_command(cmd.clone());
+                let handler = &mut state.listeners[index];
+                if handler.in_cooldown() {
+                    log::debug!(
+                        "Listener {} fired within its cooldown, skipping timeout command",
+                        index + 1
+                    );
+                    handler.suppressed = true;
+                    return;
+                }
+
+                let grace_ms = handler.config.grace_ms;
+
+                if state.listener_priority == ListenerPriority::First {
+                    match state.idle_owner {
+                        Some(owner) if owner != index => {
+                            log::debug!(
+                                "Listener {} fired after listener {} in the same idle period, skipping timeout command",
+                                index + 1,
+                                owner + 1
+                            );
+                            state.listeners[index].suppressed = true;
+                            return;
+                        }
+                        _ => state.idle_owner = Some(index),
+                    }
+                }
+
+                match grace_ms {
+                    Some(grace_ms) => state.start_grace_period(index, grace_ms),
+                    None => state.run_timeout(index),
                 }
-                state.state.set_lock_state(LockState::Locked);
             }
             ext_idle_notification_v1::Event::Resumed => {
-                if let Some(cmd) = handler.on_resume() {
+                // Activity ends the idle period, every listener resumes at once.
+                if state.idle_owner == Some(index) {
+                    state.idle_owner = None;
+                }
+
+                let handler = &mut state.listeners[index];
+                if handler.suppressed {
+                    log::debug!(
+                        "Listener {} resumed after a suppressed timeout, skipping resume command",
+                        index + 1
+                    );
+                    handler.suppressed = false;
+                    return;
+                }
+
+                if let Some(token) = handler.grace_timer.take() {
+                    log::info!("Activity during grace period, skipping timeout command");
+                    state.loop_handle.remove(token);
+                    return;
+                }
+
+                handler.idled = false;
+                if let Some(cmd) = handler.unlock_cmd(&state.config) {
+                    log::info!("Executing unlock command: {cmd}");
+                    let context = CommandContext::new("unlock", &state.power)
+                        .listener(&handler.config, handler.timeout);
+                    state.commands.run(cmd.clone(), &context);
+                }
+                if let Some(cmd) = handler.on_resume(&state.power) {
                     log::info!("Executing resume command: {cmd}");
-                    execute_command(cmd.clone());
+                    let context = CommandContext::new("resume", &state.power)
+                        .listener(&handler.config, handler.timeout);
+                    state.commands.run(cmd.clone(), &context);
                 }
-                state.state.set_lock_state(LockState::Unlocked);
+                if handler.config.sets_lock_state() {
+                    state.set_lock_state(LockState::Unlocked);
+                } else {
+                    state.update_screensaver_active();
+                }
+                state.update_fired_listeners();
             }
             _ => (),
         }
@@ -451,13 +1614,25 @@ impl Dispatch<ext_idle_notification_v1::ExtIdleNotificationV1, ()> for Moxidle {
 
 impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for Moxidle {
     fn event(
-        _: &mut Self,
-        _: &wl_registry::WlRegistry,
-        _: wl_registry::Event,
-        _: &GlobalListContents,
+        state: &mut Self,
+        registry: &wl_registry::WlRegistry,
+        event: wl_registry::Event,
+        contents: &GlobalListContents,
         _: &Connection,
         _: &QueueHandle<Self>,
     ) {
+        match event {
+            wl_registry::Event::GlobalRemove { name } if name == state.notifier_name => {
+                state.handle_notifier_removed(registry, contents);
+            }
+            wl_registry::Event::Global { interface, .. }
+                if state.notifier_lost
+                    && interface == ext_idle_notifier_v1::ExtIdleNotifierV1::interface().name =>
+            {
+                state.rebind_notifier(registry, contents);
+            }
+            _ => {}
+        }
     }
 }
 
@@ -465,9 +1640,36 @@ delegate_noop!(Moxidle: wl_pointer::WlPointer);
 delegate_noop!(Moxidle: ext_idle_notifier_v1::ExtIdleNotifierV1);
 delegate_noop!(Moxidle: ignore wl_seat::WlSeat);
 
+#[derive(clap::Subcommand)]
+enum Action {
+    /// Lock the session through the org.freedesktop.ScreenSaver interface
+    Lock,
+    /// Reset the idle timers of the running daemon
+    Reset,
+    /// Inspect or remove the screensaver inhibitors of the running daemon
+    Inhibitors {
+        #[command(subcommand)]
+        action: InhibitorsAction,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum InhibitorsAction {
+    /// List the active screensaver inhibitors
+    List,
+    /// Remove all screensaver inhibitors, e.g. ones leaked by an application
+    Clear {
+        #[arg(long, help = "Confirm removing the inhibitors")]
+        yes: bool,
+    },
+}
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    action: Option<Action>,
+
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 
@@ -476,12 +1678,170 @@ struct Cli {
 
     #[arg(short, long, value_name = "FILE", help = "Path to the config file")]
     config: Option<PathBuf>,
+
+    #[arg(long, value_name = "FILE", help = "Also write logs to this file")]
+    log_file: Option<PathBuf>,
+
+    #[arg(long, help = "Validate the config file and exit")]
+    check: bool,
+
+    #[arg(
+        long,
+        help = "Run without listeners when no config file exists at the default locations"
+    )]
+    allow_empty: bool,
+
+    #[arg(long, help = "Write an example config file and exit")]
+    init: bool,
+
+    #[arg(
+        long,
+        requires = "init",
+        help = "Overwrite an existing config file with --init"
+    )]
+    force: bool,
+}
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn check_config(path: Option<PathBuf>, allow_empty: bool) -> anyhow::Result<()> {
+    let (general, listeners) = Config::load(path, allow_empty)?;
+
+    if general.shell.argv().is_none() {
+        return Err(anyhow::anyhow!("shell is empty"));
+    }
+
+    listeners.iter().enumerate().try_for_each(|(i, listener)| {
+        if !listener.lock
+            && listener.on_warn.is_none()
+            && listener.on_timeout.is_none()
+            && listener.on_resume.is_none()
+        {
+            return Err(anyhow::anyhow!(
+                "listener {} has none of `lock`, `on_warn`, `on_timeout` or `on_resume` set",
+                i + 1
+            ));
+        }
+
+        Ok(())
+    })?;
+
+    config::duplicate_listeners(&listeners)
+        .into_iter()
+        .for_each(|(first, second)| {
+            eprintln!(
+                "Warning: listeners {first} and {second} have the same timeout and conditions"
+            );
+        });
+
+    println!("Config is valid, {} listener(s):", listeners.len());
+    listeners.iter().enumerate().for_each(|(i, listener)| {
+        println!("  {}. timeout: {}ms", i + 1, listener.timeout_millis());
+        if !listener.conditions.is_empty() {
+            println!(
+                "     conditions ({:?}): {:?}",
+                listener.condition_match, listener.conditions
+            );
+        }
+        if listener.lock {
+            println!("     lock: true");
+        }
+        if let Some(cmd) = listener.on_warn.as_ref() {
+            println!("     on_warn: {cmd}");
+        }
+        if let Some(cmd) = listener.on_timeout.as_ref() {
+            println!("     on_timeout: {cmd}");
+        }
+        if let Some(cmd) = listener.on_resume.as_ref() {
+            println!("     on_resume: {cmd}");
+        }
+    });
+
+    Ok(())
+}
+
+fn print_inhibitors(inhibitors: &[(String, String, u32)]) {
+    if inhibitors.is_empty() {
+        println!("No active inhibitors");
+        return;
+    }
+
+    let width = |column: fn(&(String, String, u32)) -> usize, header: &str| {
+        inhibitors
+            .iter()
+            .map(column)
+            .max()
+            .unwrap_or(0)
+            .max(header.len())
+    };
+    let application = width(|inhibitor| inhibitor.0.len(), "APPLICATION");
+    let reason = width(|inhibitor| inhibitor.1.len(), "REASON");
+
+    println!(
+        "{:<application$}  {:<reason$}  COOKIE",
+        "APPLICATION", "REASON"
+    );
+    for (application_name, reason_for_inhibit, cookie) in inhibitors {
+        println!("{application_name:<application$}  {reason_for_inhibit:<reason$}  {cookie}");
+    }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    if let Some(action) = cli.action {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        return match action {
+            Action::Lock => runtime
+                .block_on(screensaver::lock())
+                .map_err(|e| anyhow::anyhow!("Failed to lock the session: {e}")),
+            Action::Reset => runtime
+                .block_on(control::simulate_activity())
+                .map(|_| println!("Idle timers reset"))
+                .map_err(|e| anyhow::anyhow!("Failed to reach moxidle, is it running? {e}")),
+            Action::Inhibitors {
+                action: InhibitorsAction::List,
+            } => runtime
+                .block_on(control::list_inhibitors())
+                .map(|inhibitors| print_inhibitors(&inhibitors))
+                .map_err(|e| anyhow::anyhow!("Failed to reach moxidle, is it running? {e}")),
+            Action::Inhibitors {
+                action: InhibitorsAction::Clear { yes: false },
+            } => Err(anyhow::anyhow!(
+                "Refusing to remove inhibitors without --yes, applications holding them won't be told"
+            )),
+            Action::Inhibitors {
+                action: InhibitorsAction::Clear { yes: true },
+            } => runtime
+                .block_on(control::clear_inhibitors())
+                .map(|count| println!("Removed {count} inhibitor(s)"))
+                .map_err(|e| anyhow::anyhow!("Failed to reach moxidle, is it running? {e}")),
+        };
+    }
+
+    if cli.init {
+        let path = Config::init(cli.config, cli.force)?;
+        println!("Wrote example config to {}", path.display());
+        return Ok(());
+    }
+
+    if cli.check {
+        return check_config(cli.config, cli.allow_empty);
+    }
+
+    // Signals have to be blocked before any other thread is spawned so that every thread
+    // inherits the mask and they only get delivered through the event loop.
+    let signals = Signals::new(&[Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP])?;
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(cli, signals))
+}
 
+async fn run(cli: Cli, signals: Signals) -> anyhow::Result<()> {
     let mut log_level = LevelFilter::Info;
 
     (0..cli.verbose).for_each(|_| {
@@ -504,7 +1864,21 @@ async fn main() -> anyhow::Result<()> {
         };
     });
 
-    Builder::new().filter(Some("moxidle"), log_level).init();
+    let (general_config, listener_configs) = Config::load(cli.config.clone(), cli.allow_empty)?;
+
+    logging::init(
+        log_level,
+        cli.log_file
+            .as_deref()
+            .or(general_config.log_file.as_deref()),
+        general_config.log_max_size,
+    )?;
+
+    config::duplicate_listeners(&listener_configs)
+        .into_iter()
+        .for_each(|(first, second)| {
+            log::warn!("Listeners {first} and {second} have the same timeout and conditions");
+        });
 
     let conn = Connection::connect_to_env()?;
     let (globals, event_queue) = registry_queue_init(&conn)?;
@@ -512,99 +1886,296 @@ async fn main() -> anyhow::Result<()> {
 
     let mut event_loop = EventLoop::try_new()?;
     let (emit_sender, emit_receiver) = mpsc::channel();
-    let usb_context = rusb::Context::new();
-    let mut moxidle = Moxidle::new(globals, qh, cli.config, emit_sender, usb_context.ok())?;
+    let (control_sender, control_receiver) = tokio_mpsc::unbounded_channel();
+    let mut moxidle = Moxidle::new(
+        globals,
+        qh,
+        event_loop.handle(),
+        general_config,
+        listener_configs,
+        emit_sender,
+        control_sender,
+    )?;
+    moxidle.config_path = cli.config;
+    moxidle.allow_empty = cli.allow_empty;
+    if moxidle.state.lock_state == LockState::Locked {
+        moxidle.arm_resume_probe();
+    }
 
     WaylandSource::new(conn, event_queue).insert(event_loop.handle())?;
 
     let (executor, scheduler) = calloop::futures::executor()?;
     let (event_sender, event_receiver) = calloop::channel::channel();
 
-    let dbus_conn = Arc::new(zbus::Connection::system().await?);
-    {
-        let ignore_on_battery = moxidle.should_ignore(|c| *c == Condition::OnBattery);
-        let ignore_battery_percentage = moxidle.should_ignore(|c| {
-            matches!(
-                c,
-                Condition::BatteryBelow(_)
-                    | Condition::BatteryAbove(_)
-                    | Condition::BatteryEqual(_)
+    let dbus_conn =
+        Arc::new(retry::with_backoff("connect to the system bus", zbus::Connection::system).await?);
+    if moxidle.backend_enabled(Backend::Upower) {
+        let ignored = upower::Ignored {
+            on_battery: moxidle.should_ignore(|c| *c == Condition::OnBattery),
+            battery_percentage: moxidle.on_battery_below.is_empty()
+                && moxidle.on_battery_above.is_empty()
+                && moxidle.should_ignore(|c| {
+                    matches!(
+                        c,
+                        Condition::BatteryBelow(_)
+                            | Condition::BatteryAbove(_)
+                            | Condition::BatteryEqual(_)
+                    )
+                }),
+            battery_state: moxidle.should_ignore(|c| {
+                matches!(
+                    c,
+                    Condition::BatteryState(_)
+                        | Condition::Charging
+                        | Condition::Discharging
+                        | Condition::FullyCharged
+                )
+            }),
+            battery_level: moxidle.should_ignore(|c| matches!(c, Condition::BatteryLevel(_))),
+            time_to_empty: moxidle.should_ignore(|c| matches!(c, Condition::TimeToEmptyBelow(_))),
+            charger_online: moxidle.should_ignore(|c| *c == Condition::ChargerOnline),
+        };
+        let battery_device = moxidle.battery_device.clone();
+
+        let event_sender = event_sender.clone();
+        let dbus_conn = Arc::clone(&dbus_conn);
+        scheduler.schedule(supervise::supervise("D-Bus upower", move || {
+            upower::serve(
+                Arc::clone(&dbus_conn),
+                event_sender.clone(),
+                ignored,
+                battery_device.clone(),
             )
-        });
-        let ignore_battery_state =
-            moxidle.should_ignore(|c| matches!(c, Condition::BatteryState(_)));
-        let ignore_battery_level =
-            moxidle.should_ignore(|c| matches!(c, Condition::BatteryLevel(_)));
+        }))?;
+    }
 
+    #[cfg(feature = "network")]
+    if moxidle.backend_enabled(Backend::Network) {
+        let ignore_network = moxidle
+            .should_ignore(|c| matches!(c, Condition::NetworkConnected | Condition::Ssid(_)));
         let event_sender = event_sender.clone();
         let dbus_conn = Arc::clone(&dbus_conn);
+        scheduler.schedule(supervise::supervise("D-Bus NetworkManager", move || {
+            network::serve(Arc::clone(&dbus_conn), event_sender.clone(), ignore_network)
+        }))?;
+    }
+
+    #[cfg(feature = "portal")]
+    if moxidle.backend_enabled(Backend::Portal) {
+        let ignore_screencast = !moxidle.inhibit_on_screencast
+            && moxidle.should_ignore(|c| *c == Condition::ScreenCasting);
+        let event_sender = event_sender.clone();
+        scheduler.schedule(supervise::supervise("Portal screen cast", move || {
+            portal::serve(event_sender.clone(), ignore_screencast)
+        }))?;
+    }
+
+    let screensaver_inhibitors = Arc::new(tokio::sync::Mutex::new(Vec::new()));
+
+    if moxidle.backend_enabled(Backend::Screensaver) {
+        let ignore_dbus_inhibit = moxidle.ignore_dbus_inhibit;
+        let inhibit_filter = screensaver::InhibitFilter::new(
+            moxidle.inhibit_allowlist.clone(),
+            moxidle.inhibit_denylist.clone(),
+        );
+        let max_inhibit = moxidle.max_inhibit_secs.map(Duration::from_secs);
+        let replace_screensaver = moxidle.replace_screensaver();
+        let screensaver_inhibitors = Arc::clone(&screensaver_inhibitors);
+        let event_sender = event_sender.clone();
         scheduler.schedule(async move {
-            if let Err(e) = upower::serve(
-                dbus_conn,
+            if let Err(e) = screensaver::serve(
                 event_sender,
-                ignore_on_battery,
-                ignore_battery_percentage,
-                ignore_battery_state,
-                ignore_battery_level,
+                emit_receiver,
+                ignore_dbus_inhibit,
+                inhibit_filter,
+                screensaver_inhibitors,
+                max_inhibit,
+                replace_screensaver,
             )
             .await
             {
-                log::error!("D-Bus upower error: {e}");
+                log::error!("D-Bus screensaver error: {e}");
             }
         })?;
     }
 
+    if moxidle.backend_enabled(Backend::Login) {
+        let ignore_systemd_inhibit = moxidle.ignore_systemd_inhibit;
+        let ignore_lid = moxidle.on_lid_close.is_none()
+            && moxidle.on_lid_open.is_none()
+            && moxidle.should_ignore(|c| *c == Condition::LidClosed);
+        let ignore_session_active = !moxidle.require_active_session
+            && moxidle.should_ignore(|c| *c == Condition::SessionActive);
+        let event_sender = event_sender.clone();
+        let dbus_conn = Arc::clone(&dbus_conn);
+        scheduler.schedule(supervise::supervise("D-Bus login manager", move || {
+            login::serve(
+                Arc::clone(&dbus_conn),
+                event_sender.clone(),
+                ignore_systemd_inhibit,
+                ignore_lid,
+                ignore_session_active,
+            )
+        }))?;
+    }
+
     {
-        let ignore_dbus_inhibit = moxidle.ignore_dbus_inhibit;
         let event_sender = event_sender.clone();
         scheduler.schedule(async move {
             if let Err(e) =
-                screensaver::serve(event_sender, emit_receiver, ignore_dbus_inhibit).await
+                control::serve(event_sender, control_receiver, screensaver_inhibitors).await
             {
-                log::error!("D-Bus screensaver error: {e}");
+                log::error!("D-Bus control error: {e}");
             }
         })?;
     }
 
-    {
-        let ignore_systemd_inhibit = moxidle.ignore_systemd_inhibit;
+    #[cfg(feature = "pipewire")]
+    if moxidle.backend_enabled(Backend::Audio) {
+        let ignore_audio_inhibit = moxidle.ignore_audio_inhibit;
+        let ignore_audio_capture = moxidle.ignore_audio_capture;
+        if moxidle.audio_inhibit_roles.is_some() {
+            log::warn!("audio_inhibit_roles isn't supported by the PipeWire backend, ignoring it");
+        }
         let event_sender = event_sender.clone();
-        let dbus_conn = Arc::clone(&dbus_conn);
-        scheduler.schedule(async move {
-            if let Err(e) = login::serve(dbus_conn, event_sender, ignore_systemd_inhibit).await {
-                log::error!("D-Bus login manager error: {e}");
-            }
-        })?;
+        scheduler.schedule(supervise::supervise("PipeWire", move || {
+            pipewire::serve(
+                event_sender.clone(),
+                ignore_audio_inhibit,
+                ignore_audio_capture,
+            )
+        }))?;
     }
 
-    #[cfg(feature = "audio")]
-    {
+    #[cfg(all(feature = "audio", not(feature = "pipewire")))]
+    if moxidle.backend_enabled(Backend::Audio) {
         let ignore_audio_inhibit = moxidle.ignore_audio_inhibit;
+        let ignore_audio_capture = moxidle.ignore_audio_capture;
+        let roles = moxidle.audio_inhibit_roles.clone().map(Arc::from);
         let event_sender = event_sender.clone();
-        scheduler.schedule(async move {
-            if let Err(e) = audio::serve(event_sender, ignore_audio_inhibit).await {
-                log::error!("Audio error: {e}");
-            }
-        })?;
+        scheduler.schedule(supervise::supervise("Audio", move || {
+            audio::serve(
+                event_sender.clone(),
+                ignore_audio_inhibit,
+                ignore_audio_capture,
+                roles.clone(),
+            )
+        }))?;
     }
 
     if let Some(usb_context) = moxidle.usb_context.as_ref() {
         let event_sender = event_sender.clone();
-        usb::serve(event_sender, usb_context.clone())?;
+        match usb::serve(event_sender, usb_context.clone()) {
+            Ok(()) => moxidle.usb_devices = Some(usb::DeviceCache::default()),
+            Err(e) => log::warn!("USB hotplug unavailable, devices won't be tracked: {e}"),
+        }
+
+        match usb::pollfds(usb_context) {
+            Some(pollfds) => pollfds.into_iter().try_for_each(|pollfd| {
+                let usb_context = usb_context.clone();
+                // SAFETY: libusb keeps its descriptors open for as long as the context lives,
+                // and the context is kept alive by the callback.
+                let fd = unsafe { BorrowedFd::borrow_raw(pollfd.fd) };
+                let interest = Interest {
+                    readable: pollfd.readable,
+                    writable: pollfd.writable,
+                };
+
+                event_loop
+                    .handle()
+                    .insert_source(Generic::new(fd, interest, Mode::Level), move |_, _, _| {
+                        if let Err(e) = usb_context.handle_events(Some(Duration::ZERO)) {
+                            log::error!("USB event handling error: {e}");
+                        }
+
+                        Ok(PostAction::Continue)
+                    })
+                    .map(|_| ())
+                    .map_err(|e| anyhow::anyhow!("Failed to insert USB event source: {e}"))
+            })?,
+            None => {
+                let usb_context = usb_context.clone();
+                let interval = moxidle.usb_poll_interval();
+                event_loop
+                    .handle()
+                    .insert_source(calloop::timer::Timer::immediate(), move |_, _, _| {
+                        if let Err(e) = usb_context.handle_events(Some(Duration::ZERO)) {
+                            log::error!("USB event handling error: {e}");
+                        }
+
+                        calloop::timer::TimeoutAction::ToDuration(interval)
+                    })
+                    .map_err(|e| anyhow::anyhow!("Failed to insert USB event source: {e}"))?;
+            }
+        }
+    }
 
-        let usb_context = usb_context.clone();
+    if !moxidle.should_ignore(|c| matches!(c, Condition::TimeRange { .. })) {
         event_loop
             .handle()
-            .insert_source(calloop::timer::Timer::immediate(), move |_, _, _| {
-                if let Err(e) = usb_context.handle_events(None) {
-                    log::error!("USB event handling error: {e}");
-                }
+            .insert_source(
+                calloop::timer::Timer::from_duration(until_next_minute()),
+                |_, _, state| {
+                    state.reset_idle_timers();
+                    calloop::timer::TimeoutAction::ToDuration(until_next_minute())
+                },
+            )
+            .map_err(|e| anyhow::anyhow!("Failed to insert time range timer: {e}"))?;
+    }
 
-                calloop::timer::TimeoutAction::ToInstant(
-                    std::time::Instant::now() + std::time::Duration::from_millis(100),
-                )
-            })
-            .map_err(|e| anyhow::anyhow!("Failed to insert USB event source: {e}"))?;
+    if !moxidle.should_ignore(|c| matches!(c, Condition::ProcessRunning(_))) {
+        let interval = moxidle.process_poll_interval();
+        event_loop
+            .handle()
+            .insert_source(
+                calloop::timer::Timer::from_duration(interval),
+                move |_, _, state| {
+                    state.reset_idle_timers();
+                    calloop::timer::TimeoutAction::ToDuration(interval)
+                },
+            )
+            .map_err(|e| anyhow::anyhow!("Failed to insert process poll timer: {e}"))?;
+    }
+
+    if !moxidle.should_ignore(|c| matches!(c, Condition::CpuLoadAbove(_))) {
+        let interval = moxidle.cpu_poll_interval();
+        event_loop
+            .handle()
+            .insert_source(
+                calloop::timer::Timer::from_duration(interval),
+                move |_, _, state| {
+                    state.reset_idle_timers();
+                    calloop::timer::TimeoutAction::ToDuration(interval)
+                },
+            )
+            .map_err(|e| anyhow::anyhow!("Failed to insert CPU load poll timer: {e}"))?;
+    }
+
+    if !moxidle.should_ignore(|c| matches!(c, Condition::TempAbove { .. })) {
+        // Missing sensors are only reported once, conditions reading them are never met.
+        moxidle
+            .listeners
+            .iter()
+            .flat_map(|handler| handler.config.conditions.iter())
+            .filter_map(Condition::temp_sensor)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .filter(|sensor| thermal::temperature(sensor).is_none())
+            .for_each(|sensor| {
+                log::warn!("Temperature sensor '{sensor}' not found in /sys/class/hwmon");
+            });
+
+        let interval = moxidle.temp_poll_interval();
+        event_loop
+            .handle()
+            .insert_source(
+                calloop::timer::Timer::from_duration(interval),
+                move |_, _, state| {
+                    state.reset_idle_timers();
+                    calloop::timer::TimeoutAction::ToDuration(interval)
+                },
+            )
+            .map_err(|e| anyhow::anyhow!("Failed to insert temperature poll timer: {e}"))?;
     }
 
     event_loop
@@ -621,6 +2192,62 @@ async fn main() -> anyhow::Result<()> {
         })
         .map_err(|e| anyhow::anyhow!("{e}"))?;
 
+    let event_socket = moxidle.event_socket.clone();
+    if let Some(path) = event_socket.as_deref() {
+        event_socket::serve(path, &event_loop.handle())?;
+    }
+
+    #[cfg(feature = "metrics")]
+    if let Some(address) = moxidle.metrics_address {
+        metrics::serve(address, &event_loop.handle())?;
+    }
+
+    let loop_signal = event_loop.get_signal();
+    event_loop
+        .handle()
+        .insert_source(signals, move |event, _, state| {
+            if event.signal() == Signal::SIGHUP {
+                log::info!("Received SIGHUP, reloading config");
+                if let Err(e) = state.reload() {
+                    log::error!("Failed to reload config, keeping the current one: {e}");
+                }
+                return;
+            }
+
+            log::info!("Received {:?}, shutting down", event.signal());
+
+            let commands = state.shutdown();
+            let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+            while !commands.iter().all(|command| command.is_finished()) {
+                if Instant::now() >= deadline {
+                    log::warn!(
+                        "Commands didn't finish within {SHUTDOWN_TIMEOUT:?}, exiting anyway"
+                    );
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+
+            loop_signal.stop();
+        })
+        .map_err(|e| anyhow::anyhow!("Failed to insert signal source: {e}"))?;
+
+    // D-Bus services are registered by the executor once the event loop starts running,
+    // so they might not be available yet when this runs.
+    if let Some(cmd) = moxidle.on_start_cmd.as_ref() {
+        log::info!("Executing start command: {cmd}");
+        moxidle
+            .commands
+            .run(cmd.clone(), &CommandContext::new("start", &moxidle.power));
+    }
+
     event_loop.run(None, &mut moxidle, |_| {})?;
+
+    if let Some(path) = event_socket
+        && let Err(e) = std::fs::remove_file(&path)
+    {
+        log::warn!("Failed to remove event socket {}: {e}", path.display());
+    }
+
     Ok(())
 }
@@ -0,0 +1,199 @@
+use crate::{LockState, Moxidle};
+use calloop::{Interest, LoopHandle, Mode, PostAction, generic::Generic};
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+};
+
+// Requests are tiny, anything bigger is not a scrape.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+// Escapes a label value for the text exposition format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render(state: &Moxidle) -> String {
+    let mut body = String::new();
+    let mut gauge = |name: &str, help: &str, value: f64| {
+        let _ = writeln!(body, "# HELP {name} {help}");
+        let _ = writeln!(body, "# TYPE {name} gauge");
+        let _ = writeln!(body, "{name} {value}");
+    };
+    gauge(
+        "moxidle_locked",
+        "Whether the session is locked.",
+        f64::from(u8::from(state.state.lock_state == LockState::Locked)),
+    );
+    gauge(
+        "moxidle_active_inhibitors",
+        "Number of inhibitor sources currently inhibiting idle.",
+        state.inhibitors.count() as f64,
+    );
+    gauge(
+        "moxidle_battery_percentage",
+        "Battery percentage reported by UPower.",
+        state.power.percentage(),
+    );
+
+    let _ = writeln!(
+        body,
+        "# HELP moxidle_commands_executed_total Commands started, including queued ones."
+    );
+    let _ = writeln!(body, "# TYPE moxidle_commands_executed_total counter");
+    let _ = writeln!(
+        body,
+        "moxidle_commands_executed_total {}",
+        state.commands.executed()
+    );
+
+    let _ = writeln!(
+        body,
+        "# HELP moxidle_listener_fired_total Times a listener's timeout fired since it was loaded."
+    );
+    let _ = writeln!(body, "# TYPE moxidle_listener_fired_total counter");
+    for (index, handler) in state.listeners.iter().enumerate() {
+        // Named listeners keep their series when others are added or removed.
+        let label = match &handler.config.name {
+            Some(name) => escape_label(name),
+            None => (index + 1).to_string(),
+        };
+        let _ = writeln!(
+            body,
+            "moxidle_listener_fired_total{{listener=\"{label}\"}} {}",
+            handler.fired_count
+        );
+    }
+
+    body
+}
+
+fn render_response(request: &[u8], state: &Moxidle) -> Vec<u8> {
+    let request_line = request
+        .split(|&byte| byte == b'\n')
+        .next()
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render(state);
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .into_bytes()
+        }
+        _ => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+    }
+}
+
+// Writes as much of the response as the socket takes, returns whether all of it was written.
+fn write_response(
+    mut stream: &TcpStream,
+    response: &[u8],
+    written: &mut usize,
+) -> io::Result<bool> {
+    while *written < response.len() {
+        match stream.write(&response[*written..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => *written += n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(true)
+}
+
+// Reads the request off the event loop and answers once the headers are complete, the
+// response is written whenever the socket is writable so a slow client never blocks the loop.
+fn handle_client(stream: TcpStream, loop_handle: &LoopHandle<'static, Moxidle>) {
+    let mut request = Vec::new();
+    let mut response = None;
+    let mut written = 0;
+    let result = loop_handle.insert_source(
+        Generic::new(stream, Interest::BOTH, Mode::Edge),
+        move |_, stream, state| {
+            let mut stream: &TcpStream = stream.as_ref();
+            if response.is_none() {
+                let mut buf = [0; 1024];
+                loop {
+                    match stream.read(&mut buf) {
+                        Ok(0) => return Ok(PostAction::Remove),
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            log::debug!("Dropping metrics client: {e}");
+                            return Ok(PostAction::Remove);
+                        }
+                    }
+                }
+
+                if request.len() > MAX_REQUEST_SIZE {
+                    return Ok(PostAction::Remove);
+                }
+                if !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    return Ok(PostAction::Continue);
+                }
+                response = Some(render_response(&request, state));
+            }
+
+            let Some(response) = &response else {
+                return Ok(PostAction::Continue);
+            };
+            match write_response(stream, response, &mut written) {
+                Ok(false) => Ok(PostAction::Continue),
+                Ok(true) => Ok(PostAction::Remove),
+                Err(e) => {
+                    log::debug!("Failed to answer metrics client: {e}");
+                    Ok(PostAction::Remove)
+                }
+            }
+        },
+    );
+
+    if let Err(e) = result {
+        log::warn!("Failed to insert metrics client source: {e}");
+    }
+}
+
+pub fn serve(
+    address: SocketAddr,
+    loop_handle: &LoopHandle<'static, Moxidle>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address)
+        .map_err(|e| anyhow::anyhow!("Failed to bind metrics endpoint {address}: {e}"))?;
+    listener.set_nonblocking(true)?;
+    log::info!("Serving metrics on http://{address}/metrics");
+
+    let client_handle = loop_handle.clone();
+    loop_handle
+        .insert_source(
+            Generic::new(listener, Interest::READ, Mode::Level),
+            move |_, listener, _| {
+                loop {
+                    match listener.as_ref().accept() {
+                        Ok((stream, _)) => match stream.set_nonblocking(true) {
+                            Ok(()) => handle_client(stream, &client_handle),
+                            Err(e) => log::warn!("Failed to configure metrics client: {e}"),
+                        },
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            log::warn!("Failed to accept metrics client: {e}");
+                            break;
+                        }
+                    }
+                }
+
+                Ok(PostAction::Continue)
+            },
+        )
+        .map(|_| ())
+        .map_err(|e| anyhow::anyhow!("Failed to insert metrics source: {e}"))
+}
@@ -0,0 +1,137 @@
+use crate::{Event, supervise};
+use calloop::channel;
+use futures_lite::StreamExt;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use zbus::zvariant::OwnedObjectPath;
+
+// NM_STATE_CONNECTED_GLOBAL, reported once there is full internet connectivity.
+const NM_STATE_CONNECTED_GLOBAL: u32 = 70;
+
+#[derive(Default)]
+pub struct NetworkState {
+    pub connected: bool,
+    pub ssid: Option<Arc<str>>,
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.NetworkManager",
+    default_service = "org.freedesktop.NetworkManager",
+    default_path = "/org/freedesktop/NetworkManager"
+)]
+trait NetworkManager {
+    #[zbus(property)]
+    fn state(&self) -> zbus::Result<u32>;
+
+    #[zbus(property)]
+    fn active_connections(&self) -> zbus::Result<Vec<OwnedObjectPath>>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.NetworkManager.Connection.Active",
+    default_service = "org.freedesktop.NetworkManager",
+    assume_defaults = false
+)]
+trait ActiveConnection {
+    #[zbus(property, name = "Type")]
+    fn connection_type(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn specific_object(&self) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.NetworkManager.AccessPoint",
+    default_service = "org.freedesktop.NetworkManager",
+    assume_defaults = false
+)]
+trait AccessPoint {
+    #[zbus(property)]
+    fn ssid(&self) -> zbus::Result<Vec<u8>>;
+}
+
+// Returns the SSID of the first active Wi-Fi connection. For those, NetworkManager points
+// SpecificObject at the access point in use.
+async fn ssid(
+    connection: &zbus::Connection,
+    active_connections: Vec<OwnedObjectPath>,
+) -> zbus::Result<Option<Arc<str>>> {
+    for path in active_connections {
+        let active = ActiveConnectionProxy::builder(connection)
+            .path(path)?
+            .build()
+            .await?;
+        if active.connection_type().await? != "802-11-wireless" {
+            continue;
+        }
+
+        let access_point = AccessPointProxy::builder(connection)
+            .path(active.specific_object().await?)?
+            .build()
+            .await?;
+        let ssid = access_point.ssid().await?;
+        return Ok(Some(String::from_utf8_lossy(&ssid).into()));
+    }
+
+    Ok(None)
+}
+
+async fn handle_network_state(
+    connection: &zbus::Connection,
+    network_manager: &NetworkManagerProxy<'_>,
+    event_sender: &channel::Sender<Event>,
+) {
+    let connected = match network_manager.state().await {
+        Ok(state) => state == NM_STATE_CONNECTED_GLOBAL,
+        Err(e) => {
+            log::warn!("Failed to get NetworkManager state: {e}");
+            false
+        }
+    };
+
+    let ssid = match network_manager.active_connections().await {
+        Ok(active_connections) => ssid(connection, active_connections)
+            .await
+            .unwrap_or_else(|e| {
+                log::warn!("Failed to get Wi-Fi SSID: {e}");
+                None
+            }),
+        Err(e) => {
+            log::warn!("Failed to get NetworkManager active connections: {e}");
+            None
+        }
+    };
+
+    if let Err(e) = event_sender.send(Event::NetworkState { connected, ssid }) {
+        log::warn!("Failed to send NetworkState event: {e}");
+    }
+}
+
+pub async fn serve(
+    connection: Arc<zbus::Connection>,
+    event_sender: channel::Sender<Event>,
+    ignore_network: bool,
+) -> anyhow::Result<()> {
+    if ignore_network {
+        return Ok(());
+    }
+
+    let network_manager = NetworkManagerProxy::new(&connection).await?;
+    let state_stream = network_manager.receive_state_changed().await;
+    let active_connections_stream = network_manager.receive_active_connections_changed().await;
+    log::info!("NetworkManager listener active");
+
+    handle_network_state(&connection, &network_manager, &event_sender).await;
+
+    let mut tasks = JoinSet::new();
+    tasks.spawn(async move {
+        let mut changes = state_stream
+            .map(|_| ())
+            .or(active_connections_stream.map(|_| ()));
+        while changes.next().await.is_some() {
+            handle_network_state(&connection, &network_manager, &event_sender).await;
+        }
+    });
+
+    supervise::join(tasks).await
+}
@@ -0,0 +1,116 @@
+use crate::Moxidle;
+use std::collections::HashMap;
+use wayland_client::{
+    Connection, Dispatch, Proxy, QueueHandle, backend::ObjectId, event_created_child,
+};
+use wayland_protocols_wlr::output_management::v1::client::{
+    zwlr_output_head_v1::{self, ZwlrOutputHeadV1},
+    zwlr_output_manager_v1::{self, ZwlrOutputManagerV1},
+    zwlr_output_mode_v1::{self, ZwlrOutputModeV1},
+};
+
+#[derive(Default)]
+struct Head {
+    name: String,
+    description: String,
+    enabled: bool,
+}
+
+#[derive(Default)]
+pub struct Outputs {
+    heads: HashMap<ObjectId, Head>,
+}
+
+impl Outputs {
+    // Matches the output name exactly, e.g. "HDMI-A-1", or any part of its description.
+    pub fn is_connected(&self, name: &str) -> bool {
+        self.heads
+            .values()
+            .any(|head| head.enabled && (head.name == name || head.description.contains(name)))
+    }
+
+    pub fn enabled_count(&self) -> usize {
+        self.heads.values().filter(|head| head.enabled).count()
+    }
+}
+
+impl Dispatch<ZwlrOutputManagerV1, ()> for Moxidle {
+    fn event(
+        state: &mut Self,
+        _: &ZwlrOutputManagerV1,
+        event: zwlr_output_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_output_manager_v1::Event::Head { head } => {
+                state.outputs.heads.insert(head.id(), Head::default());
+            }
+            // Head properties are sent in batches, only act on them once the batch is complete.
+            zwlr_output_manager_v1::Event::Done { .. } => state.reset_idle_timers(),
+            zwlr_output_manager_v1::Event::Finished => {
+                state.outputs.heads.clear();
+                state.reset_idle_timers();
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(Moxidle, ZwlrOutputManagerV1, [
+        zwlr_output_manager_v1::EVT_HEAD_OPCODE => (ZwlrOutputHeadV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrOutputHeadV1, ()> for Moxidle {
+    fn event(
+        state: &mut Self,
+        head: &ZwlrOutputHeadV1,
+        event: zwlr_output_head_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let zwlr_output_head_v1::Event::Finished = event {
+            state.outputs.heads.remove(&head.id());
+            if head.version() >= 3 {
+                head.release();
+            }
+            return;
+        }
+
+        let Some(entry) = state.outputs.heads.get_mut(&head.id()) else {
+            return;
+        };
+
+        match event {
+            zwlr_output_head_v1::Event::Name { name } => entry.name = name,
+            zwlr_output_head_v1::Event::Description { description } => {
+                entry.description = description
+            }
+            zwlr_output_head_v1::Event::Enabled { enabled } => entry.enabled = enabled != 0,
+            _ => {}
+        }
+    }
+
+    event_created_child!(Moxidle, ZwlrOutputHeadV1, [
+        zwlr_output_head_v1::EVT_MODE_OPCODE => (ZwlrOutputModeV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrOutputModeV1, ()> for Moxidle {
+    fn event(
+        _: &mut Self,
+        mode: &ZwlrOutputModeV1,
+        event: zwlr_output_mode_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let zwlr_output_mode_v1::Event::Finished = event
+            && mode.version() >= 3
+        {
+            mode.release();
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use crate::Event;
+use ::pipewire::{
+    self as pw,
+    node::{Node, NodeListener, NodeState},
+    types::ObjectType,
+};
+use calloop::channel;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+#[derive(Default)]
+struct Nodes {
+    // Proxies and listeners have to be kept alive to keep receiving node info.
+    bound: HashMap<u32, (Node, NodeListener)>,
+    running: HashSet<u32>,
+}
+
+fn set_running(
+    nodes: &RefCell<Nodes>,
+    id: u32,
+    running: bool,
+    event_sender: &channel::Sender<Event>,
+) {
+    let mut nodes = nodes.borrow_mut();
+    let was_inhibited = !nodes.running.is_empty();
+    if running {
+        nodes.running.insert(id);
+    } else {
+        nodes.running.remove(&id);
+    }
+
+    let inhibited = !nodes.running.is_empty();
+    if inhibited != was_inhibited {
+        let action = if inhibited { "Added" } else { "Removed" };
+        log::info!("{action} audio inhibitor for PipeWire node {id}");
+
+        if let Err(e) = event_sender.send(Event::AudioInhibit(inhibited)) {
+            log::error!("Failed to send AudioInhibit event: {e}");
+        }
+    }
+}
+
+// Only returns once the connection to the daemon is lost, e.g. when it restarts.
+fn run(event_sender: channel::Sender<Event>, ignore_audio_capture: bool) -> anyhow::Result<()> {
+    let mainloop = pw::main_loop::MainLoop::new(None)?;
+    let context = pw::context::Context::new(&mainloop)?;
+    let core = context.connect(None)?;
+
+    let core_error = Rc::new(RefCell::new(None));
+    let _core_listener = core
+        .add_listener_local()
+        .error({
+            let mainloop = mainloop.downgrade();
+            let core_error = Rc::clone(&core_error);
+            move |id, _, res, message| {
+                if id != pw::core::PW_ID_CORE {
+                    log::warn!("PipeWire error on object {id}: {message} ({res})");
+                    return;
+                }
+
+                *core_error.borrow_mut() = Some(format!("{message} ({res})"));
+                if let Some(mainloop) = mainloop.upgrade() {
+                    mainloop.quit();
+                }
+            }
+        })
+        .register();
+
+    let registry = Rc::new(core.get_registry()?);
+    let nodes = Rc::new(RefCell::new(Nodes::default()));
+
+    let _registry_listener = registry
+        .add_listener_local()
+        .global({
+            let registry = Rc::downgrade(&registry);
+            let nodes = Rc::clone(&nodes);
+            let event_sender = event_sender.clone();
+            move |global| {
+                if global.type_ != ObjectType::Node {
+                    return;
+                }
+
+                let Some(media_class) = global.props.and_then(|props| props.get("media.class"))
+                else {
+                    return;
+                };
+
+                let watched = media_class == "Audio/Sink"
+                    || (!ignore_audio_capture && media_class == "Audio/Source");
+                if !watched {
+                    return;
+                }
+
+                let Some(registry) = registry.upgrade() else {
+                    return;
+                };
+
+                let node: Node = match registry.bind(global) {
+                    Ok(node) => node,
+                    Err(e) => {
+                        log::error!("Failed to bind PipeWire node {}: {e}", global.id);
+                        return;
+                    }
+                };
+
+                let id = global.id;
+                let listener = node
+                    .add_listener_local()
+                    .info({
+                        let nodes = Rc::downgrade(&nodes);
+                        let event_sender = event_sender.clone();
+                        move |info| {
+                            if let Some(nodes) = nodes.upgrade() {
+                                let running = matches!(info.state(), NodeState::Running);
+                                set_running(&nodes, id, running, &event_sender);
+                            }
+                        }
+                    })
+                    .register();
+
+                nodes.borrow_mut().bound.insert(id, (node, listener));
+            }
+        })
+        .global_remove({
+            let nodes = Rc::clone(&nodes);
+            move |id| {
+                if nodes.borrow_mut().bound.remove(&id).is_some() {
+                    set_running(&nodes, id, false, &event_sender);
+                }
+            }
+        })
+        .register();
+
+    mainloop.run();
+
+    let error = core_error
+        .borrow_mut()
+        .take()
+        .unwrap_or_else(|| "main loop stopped".into());
+    Err(anyhow::anyhow!("lost the connection to PipeWire: {error}"))
+}
+
+pub async fn serve(
+    event_sender: channel::Sender<Event>,
+    ignore_audio_inhibit: bool,
+    ignore_audio_capture: bool,
+) -> anyhow::Result<()> {
+    if ignore_audio_inhibit {
+        return Ok(());
+    }
+
+    // PipeWire objects aren't Send, so the whole backend lives on its own thread.
+    let thread_sender = event_sender.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        pw::init();
+        run(thread_sender, ignore_audio_capture)
+    })
+    .await;
+
+    // Whatever the thread tracked is gone with it.
+    if let Err(e) = event_sender.send(Event::AudioInhibit(false)) {
+        log::error!("Failed to send AudioInhibit event: {e}");
+    }
+
+    match result {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => Err(anyhow::anyhow!("PipeWire thread panicked")),
+        Err(e) => Err(e.into()),
+    }
+}
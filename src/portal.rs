@@ -0,0 +1,227 @@
+use crate::{Event, retry};
+use calloop::channel;
+use futures_lite::StreamExt;
+use std::collections::HashMap;
+use zbus::{
+    MatchRule, MessageStream,
+    message::{Message, Type},
+    zvariant::{OwnedObjectPath, OwnedValue},
+};
+
+const PORTAL_SERVICE: &str = "org.freedesktop.portal.Desktop";
+const REQUEST_INTERFACE: &str = "org.freedesktop.portal.Request";
+const SCREEN_CAST_INTERFACE: &str = "org.freedesktop.portal.ScreenCast";
+const SESSION_INTERFACE: &str = "org.freedesktop.portal.Session";
+
+// Screen cast sessions by session handle, with the unique name of the client that started them.
+// A session only counts once the portal answered its Start request with success, the user may
+// still cancel the source selection until then.
+#[derive(Default)]
+struct Sessions {
+    sessions: HashMap<Box<str>, Box<str>>,
+    // Start calls waiting for their reply, by sender and serial.
+    calls: HashMap<(Box<str>, u32), Box<str>>,
+    // Start requests waiting for their Response, by request handle, with session and sender.
+    requests: HashMap<Box<str>, (Box<str>, Box<str>)>,
+}
+
+impl Sessions {
+    // Returns whether the message started or ended a session.
+    fn handle(&mut self, message: &Message) -> bool {
+        let header = message.header();
+        let interface = header.interface().map(|interface| interface.as_str());
+        let member = header.member().map(|member| member.as_str());
+
+        match (header.message_type(), interface, member) {
+            (Type::MethodCall, Some(SCREEN_CAST_INTERFACE), Some("Start")) => {
+                let Ok((session, _, _)) =
+                    message
+                        .body()
+                        .deserialize::<(OwnedObjectPath, String, HashMap<String, OwnedValue>)>()
+                else {
+                    return false;
+                };
+                let sender = header
+                    .sender()
+                    .map(|sender| sender.as_str().into())
+                    .unwrap_or_default();
+                self.calls.insert(
+                    (sender, header.primary().serial_num().get()),
+                    session.as_str().into(),
+                );
+                false
+            }
+            // The reply to Start carries the handle of the request the result is sent on.
+            (Type::MethodReturn | Type::Error, _, _) => {
+                let (Some(destination), Some(serial)) =
+                    (header.destination(), header.reply_serial())
+                else {
+                    return false;
+                };
+                let key = (destination.as_str().into(), serial.get());
+                let Some(session) = self.calls.remove(&key) else {
+                    return false;
+                };
+                if let Ok(request) = message.body().deserialize::<OwnedObjectPath>() {
+                    self.requests
+                        .insert(request.as_str().into(), (session, key.0));
+                }
+                false
+            }
+            (Type::Signal, Some(REQUEST_INTERFACE), Some("Response")) => {
+                let Some((session, sender)) = header
+                    .path()
+                    .and_then(|path| self.requests.remove(path.as_str()))
+                else {
+                    return false;
+                };
+                // 0 is success, the user cancelled or the portal failed otherwise.
+                match message
+                    .body()
+                    .deserialize::<(u32, HashMap<String, OwnedValue>)>()
+                {
+                    Ok((0, _)) => self.sessions.insert(session, sender).is_none(),
+                    _ => false,
+                }
+            }
+            (Type::MethodCall, Some(SESSION_INTERFACE), Some("Close"))
+            | (Type::Signal, Some(SESSION_INTERFACE), Some("Closed")) => {
+                let Some(path) = header.path() else {
+                    return false;
+                };
+                self.calls.retain(|_, session| **session != *path.as_str());
+                self.requests
+                    .retain(|_, (session, _)| **session != *path.as_str());
+                self.sessions.remove(path.as_str()).is_some()
+            }
+            // Sessions end with the client that started them.
+            (Type::Signal, Some("org.freedesktop.DBus"), Some("NameOwnerChanged")) => {
+                let Ok((name, _, new_owner)) =
+                    message.body().deserialize::<(String, String, String)>()
+                else {
+                    return false;
+                };
+                if !new_owner.is_empty() {
+                    return false;
+                }
+
+                self.calls.retain(|(sender, _), _| **sender != *name);
+                self.requests.retain(|_, (_, sender)| **sender != *name);
+                let count = self.sessions.len();
+                self.sessions.retain(|_, sender| **sender != *name);
+                self.sessions.len() != count
+            }
+            _ => false,
+        }
+    }
+}
+
+fn rules() -> zbus::Result<[MatchRule<'static>; 7]> {
+    Ok([
+        MatchRule::builder()
+            .msg_type(Type::MethodCall)
+            .interface(SCREEN_CAST_INTERFACE)?
+            .member("Start")?
+            .build(),
+        MatchRule::builder()
+            .msg_type(Type::MethodReturn)
+            .sender(PORTAL_SERVICE)?
+            .build(),
+        MatchRule::builder()
+            .msg_type(Type::Error)
+            .sender(PORTAL_SERVICE)?
+            .build(),
+        MatchRule::builder()
+            .msg_type(Type::Signal)
+            .sender(PORTAL_SERVICE)?
+            .interface(REQUEST_INTERFACE)?
+            .member("Response")?
+            .build(),
+        MatchRule::builder()
+            .msg_type(Type::MethodCall)
+            .interface(SESSION_INTERFACE)?
+            .member("Close")?
+            .build(),
+        MatchRule::builder()
+            .msg_type(Type::Signal)
+            .interface(SESSION_INTERFACE)?
+            .member("Closed")?
+            .build(),
+        MatchRule::builder()
+            .msg_type(Type::Signal)
+            .interface("org.freedesktop.DBus")?
+            .member("NameOwnerChanged")?
+            .build(),
+    ])
+}
+
+// Whether the portal runs or can be started, monitoring for it is pointless otherwise.
+async fn is_available(connection: &zbus::Connection) -> zbus::Result<bool> {
+    let dbus = zbus::fdo::DBusProxy::new(connection).await?;
+    if dbus
+        .name_has_owner(zbus::names::BusName::try_from(PORTAL_SERVICE)?)
+        .await?
+    {
+        return Ok(true);
+    }
+
+    Ok(dbus
+        .list_activatable_names()
+        .await?
+        .iter()
+        .any(|name| name.as_str() == PORTAL_SERVICE))
+}
+
+// The portal doesn't publish its sessions, so screen casts are tracked by monitoring the calls
+// clients make to it. The monitoring connection can't be used for anything else.
+pub async fn serve(
+    event_sender: channel::Sender<Event>,
+    ignore_screencast: bool,
+) -> anyhow::Result<()> {
+    if ignore_screencast {
+        return Ok(());
+    }
+
+    let connection =
+        retry::with_backoff("connect to the session bus", zbus::Connection::session).await?;
+    match is_available(&connection).await {
+        Ok(true) => {}
+        Ok(false) => {
+            log::info!("xdg-desktop-portal isn't available, screen casts won't be detected");
+            return Ok(());
+        }
+        Err(e) => {
+            log::warn!(
+                "Failed to check for xdg-desktop-portal, screen casts won't be detected: {e}"
+            );
+            return Ok(());
+        }
+    }
+    let monitoring = zbus::fdo::MonitoringProxy::new(&connection).await?;
+    if let Err(e) = monitoring.become_monitor(&rules()?, 0).await {
+        log::warn!("Can't monitor the session bus, screen casts won't be detected: {e}");
+        return Ok(());
+    }
+    log::info!("ScreenCast listener active");
+
+    let mut sessions = Sessions::default();
+    let mut active = false;
+    let mut stream = MessageStream::from(&connection);
+    while let Some(message) = stream.next().await {
+        let Ok(message) = message else {
+            continue;
+        };
+
+        if !sessions.handle(&message) || active != sessions.sessions.is_empty() {
+            continue;
+        }
+
+        active = !active;
+        log::info!("Screen cast active: {active}");
+        if let Err(e) = event_sender.send(Event::ScreenCastActive(active)) {
+            log::error!("Failed to send ScreenCastActive event: {e}");
+        }
+    }
+
+    Err(anyhow::anyhow!("session bus monitor connection closed"))
+}
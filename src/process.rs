@@ -0,0 +1,17 @@
+use std::fs;
+
+// Matches against /proc/<pid>/comm, which holds the binary name truncated to 15 bytes.
+pub fn is_running(name: &str) -> bool {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return false;
+    };
+
+    entries.flatten().any(|entry| {
+        entry
+            .file_name()
+            .to_str()
+            .is_some_and(|pid| pid.bytes().all(|b| b.is_ascii_digit()))
+            && fs::read_to_string(entry.path().join("comm"))
+                .is_ok_and(|comm| comm.trim_end_matches('\n') == name)
+    })
+}
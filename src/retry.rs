@@ -0,0 +1,31 @@
+use std::{fmt::Display, future::Future, time::Duration};
+
+const INITIAL_DELAY: Duration = Duration::from_millis(250);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+const MAX_ATTEMPTS: u32 = 10;
+
+// Retries `f` with an exponential backoff capped at MAX_DELAY. D-Bus and the services on it
+// may not be up yet when moxidle is started early during boot.
+pub async fn with_backoff<T, E, F, Fut>(what: &str, mut f: F) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut delay = INITIAL_DELAY;
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < MAX_ATTEMPTS => {
+                log::warn!(
+                    "Failed to {what} (attempt {attempt}/{MAX_ATTEMPTS}), retrying in {delay:?}: {e}"
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_DELAY);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
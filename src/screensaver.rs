@@ -1,28 +1,66 @@
 // https://specifications.freedesktop.org/idle-inhibit-spec/latest
 // https://invent.kde.org/plasma/kscreenlocker/-/blob/master/dbus/org.freedesktop.ScreenSaver.xml
 
-use crate::{Event, LockState};
+use crate::{Event, retry};
 use calloop::channel;
 use futures_lite::StreamExt;
-use std::sync::{
-    Arc,
-    atomic::{AtomicU32, Ordering},
-    mpsc,
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+        mpsc,
+    },
+    time::{Duration, Instant},
 };
 use tokio::sync::{Mutex, oneshot};
 use zbus::object_server::SignalEmitter;
 
+const MAX_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Debug)]
-struct Inhibitor {
+pub struct Inhibitor {
+    pub cookie: u32,
+    pub application_name: Box<str>,
+    pub reason_for_inhibit: Box<str>,
+    client: zbus::names::UniqueName<'static>,
+    created: Instant,
+}
+
+pub struct InhibitFilter {
+    allowlist: Option<Box<[Arc<str>]>>,
+    denylist: Box<[Arc<str>]>,
+}
+
+impl InhibitFilter {
+    pub fn new(allowlist: Option<Box<[Arc<str>]>>, denylist: Box<[Arc<str>]>) -> Self {
+        Self {
+            allowlist,
+            denylist,
+        }
+    }
+
+    fn allows(&self, application_name: &str) -> bool {
+        !self.denylist.iter().any(|name| **name == *application_name)
+            && self
+                .allowlist
+                .as_ref()
+                .is_none_or(|allowlist| allowlist.iter().any(|name| **name == *application_name))
+    }
+}
+
+#[derive(Debug)]
+struct Throttler {
     cookie: u32,
     application_name: Box<str>,
-    reason_for_inhibit: Box<str>,
+    reason_for_throttle: Box<str>,
     client: zbus::names::UniqueName<'static>,
 }
 
 #[derive(Clone)]
 struct ScreenSaver {
     inhibitors: Arc<Mutex<Vec<Inhibitor>>>,
+    throttlers: Arc<Mutex<Vec<Throttler>>>,
+    inhibit_filter: Arc<InhibitFilter>,
     last_cookie: Arc<AtomicU32>,
     event_sender: channel::Sender<Event>,
 }
@@ -30,7 +68,7 @@ struct ScreenSaver {
 #[zbus::interface(name = "org.freedesktop.ScreenSaver")]
 impl ScreenSaver {
     #[zbus(signal)]
-    async fn active_changed(signal_emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+    async fn active_changed(signal_emitter: &SignalEmitter<'_>, active: bool) -> zbus::Result<()>;
 
     async fn lock(&self) {
         if let Err(e) = self.event_sender.send(Event::ScreenSaverLock) {
@@ -46,11 +84,14 @@ impl ScreenSaver {
 
     async fn get_active(&self) -> bool {
         let (response_tx, response_rx) = oneshot::channel();
-        if let Err(e) = self.event_sender.send(Event::GetLockState(response_tx)) {
-            log::error!("Failed to send GetActiveTime request: {e}");
+        if let Err(e) = self
+            .event_sender
+            .send(Event::GetScreenSaverActive(response_tx))
+        {
+            log::error!("Failed to send GetScreenSaverActive request: {e}");
             return false;
         }
-        response_rx.await.unwrap_or(LockState::Unlocked) == LockState::Locked
+        response_rx.await.unwrap_or(false)
     }
 
     async fn get_active_time(&self) -> u32 {
@@ -62,8 +103,16 @@ impl ScreenSaver {
         response_rx.await.unwrap_or(0)
     }
 
-    async fn get_session_idle_time(&self) -> zbus::fdo::Result<u32> {
-        Err(zbus::fdo::Error::ZBus(zbus::Error::Unsupported))
+    async fn get_session_idle_time(&self) -> u32 {
+        let (response_tx, response_rx) = oneshot::channel();
+        if let Err(e) = self
+            .event_sender
+            .send(Event::GetSessionIdleTime(response_tx))
+        {
+            log::error!("Failed to send GetSessionIdleTime request: {e}");
+            return 0;
+        }
+        response_rx.await.unwrap_or(0)
     }
 
     async fn set_active(&self, state: bool) -> bool {
@@ -81,6 +130,13 @@ impl ScreenSaver {
         #[zbus(header)] header: zbus::message::Header<'_>,
     ) -> u32 {
         let cookie = self.last_cookie.fetch_add(1, Ordering::Relaxed) + 1;
+        if !self.inhibit_filter.allows(application_name) {
+            log::info!(
+                "Suppressed screensaver inhibitor for application '{application_name}', reason: {reason_for_inhibit}, cookie: {cookie}"
+            );
+            return cookie;
+        }
+
         if let Some(sender) = header.sender() {
             log::info!(
                 "Added screensaver inhibitor for application '{application_name}' {sender:?}, reason: {reason_for_inhibit}, cookie: {cookie}"
@@ -96,6 +152,7 @@ impl ScreenSaver {
                 application_name: application_name.into(),
                 reason_for_inhibit: reason_for_inhibit.into(),
                 client: sender.to_owned(),
+                created: Instant::now(),
             });
         }
         cookie
@@ -126,52 +183,190 @@ impl ScreenSaver {
         reason_for_inhibit: &str,
         #[zbus(header)] header: zbus::message::Header<'_>,
     ) -> u32 {
-        // TODO: If anyone tells me what it's supposed to do I'd be happy to implement
-        _ = header;
-        _ = application_name;
-        _ = reason_for_inhibit;
-
-        0
+        let cookie = self.last_cookie.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(sender) = header.sender() {
+            log::info!(
+                "Added screensaver throttler for application '{application_name}' {sender:?}, reason: {reason_for_inhibit}, cookie: {cookie}"
+            );
+            let mut throttlers = self.throttlers.lock().await;
+            if throttlers.is_empty()
+                && let Err(e) = self.event_sender.send(Event::ScreenSaverThrottle(true))
+            {
+                log::error!("Failed to send ScreenSaverThrottle event {e}");
+            }
+            throttlers.push(Throttler {
+                cookie,
+                application_name: application_name.into(),
+                reason_for_throttle: reason_for_inhibit.into(),
+                client: sender.to_owned(),
+            });
+        }
+        cookie
     }
 
     async fn un_throttle(&mut self, cookie: u32) {
-        // TODO: If anyone tells me what it's supposed to do I'd be happy to implement
-        _ = cookie;
+        let mut throttlers = self.throttlers.lock().await;
+        if let Some(idx) = throttlers.iter().position(|x| x.cookie == cookie) {
+            let throttler = throttlers.remove(idx);
+            if throttlers.is_empty()
+                && let Err(e) = self.event_sender.send(Event::ScreenSaverThrottle(false))
+            {
+                log::error!("Failed to send ScreenSaverThrottle event {e}");
+            }
+            log::info!(
+                "Removed screensaver throttler for application '{}' {:?}, reason: {}, cookie: {}",
+                throttler.application_name,
+                throttler.client,
+                throttler.reason_for_throttle,
+                throttler.cookie
+            );
+        }
+    }
+}
+
+// Asks whichever screensaver owns the name to lock, trying both paths it may be served at.
+pub async fn lock() -> zbus::Result<()> {
+    let conn = zbus::Connection::session().await?;
+
+    let mut result = Ok(());
+    for path in ["/org/freedesktop/ScreenSaver", "/ScreenSaver"] {
+        result = conn
+            .call_method(
+                Some("org.freedesktop.ScreenSaver"),
+                path,
+                Some("org.freedesktop.ScreenSaver"),
+                "Lock",
+                &(),
+            )
+            .await
+            .map(|_| ());
+
+        if result.is_ok() {
+            break;
+        }
     }
+
+    result
+}
+
+const SCREENSAVER_NAME: &str = "org.freedesktop.ScreenSaver";
+
+// Takes the screensaver name, or queues for it when another owner keeps it. Without `replace`
+// the current owner is left alone and moxidle gives the name up to whoever asks for it.
+async fn request_name(conn: &zbus::Connection, replace: bool) -> zbus::Result<()> {
+    let flags = if replace {
+        zbus::fdo::RequestNameFlags::ReplaceExisting.into()
+    } else {
+        zbus::fdo::RequestNameFlags::AllowReplacement.into()
+    };
+
+    let reply = retry::with_backoff("request the screensaver name", || {
+        conn.request_name_with_flags(SCREENSAVER_NAME, flags)
+    })
+    .await?;
+
+    if reply != zbus::fdo::RequestNameReply::InQueue {
+        return Ok(());
+    }
+
+    let dbus = zbus::fdo::DBusProxy::new(conn).await?;
+    let owner = dbus
+        .get_name_owner(zbus::names::BusName::try_from(SCREENSAVER_NAME)?)
+        .await
+        .map(|owner| owner.to_string())
+        .unwrap_or_else(|_| "unknown".into());
+    log::warn!(
+        "{SCREENSAVER_NAME} is owned by another process ({owner}), waiting for it to release the name. Inhibitors sent to it won't reach moxidle until then"
+    );
+
+    let mut acquired = dbus.receive_name_acquired().await?;
+    tokio::spawn(async move {
+        while let Some(signal) = acquired.next().await {
+            if signal
+                .args()
+                .is_ok_and(|args| args.name().as_str() == SCREENSAVER_NAME)
+            {
+                log::info!("Acquired {SCREENSAVER_NAME}");
+                break;
+            }
+        }
+    });
+
+    Ok(())
 }
 
 pub async fn serve(
     event_sender: channel::Sender<Event>,
-    emit_receiver: mpsc::Receiver<()>,
+    emit_receiver: mpsc::Receiver<bool>,
     ignore_dbus_inhibit: bool,
+    inhibit_filter: InhibitFilter,
+    inhibitors: Arc<Mutex<Vec<Inhibitor>>>,
+    max_inhibit: Option<Duration>,
+    replace: bool,
 ) -> zbus::Result<()> {
     if ignore_dbus_inhibit {
         return Ok(());
     }
 
-    let inhibitors = Arc::new(Mutex::new(Vec::new()));
-
+    let throttlers = Arc::new(Mutex::new(Vec::new()));
     let screensaver = ScreenSaver {
         inhibitors: Arc::clone(&inhibitors),
+        throttlers: Arc::clone(&throttlers),
+        inhibit_filter: Arc::new(inhibit_filter),
         event_sender: event_sender.clone(),
         last_cookie: Arc::new(AtomicU32::new(0)),
     };
 
     let paths = ["/ScreenSaver", "/org/freedesktop/ScreenSaver"];
-    let conn = paths
-        .iter()
-        .try_fold(zbus::connection::Builder::session()?, |builder, &path| {
-            builder.serve_at(path, screensaver.clone())
-        })?
-        .build()
-        .await?;
-
-    conn.request_name_with_flags(
-        "org.freedesktop.ScreenSaver",
-        zbus::fdo::RequestNameFlags::ReplaceExisting.into(),
-    )
+    let conn = retry::with_backoff("connect to the session bus", || async {
+        paths
+            .iter()
+            .try_fold(zbus::connection::Builder::session()?, |builder, &path| {
+                builder.serve_at(path, screensaver.clone())
+            })?
+            .build()
+            .await
+    })
     .await?;
 
+    request_name(&conn, replace).await?;
+
+    if let Some(max_inhibit) = max_inhibit {
+        let inhibitors = Arc::clone(&inhibitors);
+        let event_sender = event_sender.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(max_inhibit.min(MAX_SWEEP_INTERVAL));
+            loop {
+                interval.tick().await;
+                let mut inhibitors = inhibitors.lock().await;
+                if inhibitors.is_empty() {
+                    continue;
+                }
+
+                inhibitors.retain(|inhibitor| {
+                    let age = inhibitor.created.elapsed();
+                    if age < max_inhibit {
+                        return true;
+                    }
+
+                    log::info!(
+                        "Expired screensaver inhibitor for application '{}' after {}s, cookie: {}",
+                        inhibitor.application_name,
+                        age.as_secs(),
+                        inhibitor.cookie
+                    );
+                    false
+                });
+
+                if inhibitors.is_empty()
+                    && let Err(e) = event_sender.send(Event::ScreenSaverInhibit(false))
+                {
+                    log::error!("Failed to send ScreenSaverInhibit(false) event: {e}");
+                }
+            }
+        });
+    }
+
     let dbus = zbus::fdo::DBusProxy::new(&conn).await?;
     let mut name_owner_stream = dbus.receive_name_owner_changed().await?;
     tokio::spawn(async move {
@@ -189,6 +384,17 @@ pub async fn serve(
                         log::error!("Failed to send ScreenSaverInhibit(false) event: {e}");
                     }
                 }
+                drop(inhibitors);
+
+                let mut throttlers = throttlers.lock().await;
+                if !throttlers.is_empty() {
+                    throttlers.retain(|throttler| throttler.client != name);
+                    if throttlers.is_empty()
+                        && let Err(e) = event_sender.send(Event::ScreenSaverThrottle(false))
+                    {
+                        log::error!("Failed to send ScreenSaverThrottle(false) event: {e}");
+                    }
+                }
             }
         }
     });
@@ -202,13 +408,17 @@ pub async fn serve(
 
     tokio::spawn(async move {
         loop {
-            if let Err(e) = emit_receiver.recv() {
-                log::error!("Failed to receive emit event: {e}");
-            }
+            let active = match emit_receiver.recv() {
+                Ok(active) => active,
+                Err(e) => {
+                    log::error!("Failed to receive emit event: {e}");
+                    break;
+                }
+            };
 
             if let Err(e) = tokio::try_join!(
-                ScreenSaver::active_changed(interfaces.0.signal_emitter()),
-                ScreenSaver::active_changed(interfaces.1.signal_emitter())
+                ScreenSaver::active_changed(interfaces.0.signal_emitter(), active),
+                ScreenSaver::active_changed(interfaces.1.signal_emitter(), active)
             ) {
                 log::error!("Failed to emit active changed event: {e}");
             }
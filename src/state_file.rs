@@ -0,0 +1,82 @@
+use std::{
+    fs, io,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Lock state kept in $XDG_RUNTIME_DIR/moxidle/state, so that a restarted moxidle knows the
+// session is still locked. The file holds "locked" or "unlocked" followed by the Unix time the
+// screensaver became active, or "-" when it isn't.
+fn path() -> Option<PathBuf> {
+    std::env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join("moxidle/state"))
+}
+
+fn boot_time() -> Option<SystemTime> {
+    let stat = fs::read_to_string("/proc/stat").ok()?;
+    let secs = stat
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+pub fn save(locked: bool, active_since: Option<Instant>) {
+    let Some(path) = path() else {
+        return;
+    };
+
+    let active_since = active_since
+        .and_then(|since| SystemTime::now().checked_sub(since.elapsed()))
+        .and_then(|since| since.duration_since(UNIX_EPOCH).ok())
+        .map_or_else(|| "-".to_string(), |since| since.as_secs().to_string());
+    let state = if locked { "locked" } else { "unlocked" };
+
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::write(&path, format!("{state} {active_since}\n")));
+    if let Err(e) = result {
+        log::warn!("Failed to save lock state to {}: {e}", path.display());
+    }
+}
+
+pub fn clear() {
+    let Some(path) = path() else {
+        return;
+    };
+
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            log::warn!("Failed to remove {}: {e}", path.display());
+        }
+        _ => (),
+    }
+}
+
+// Returns when the screensaver became active if the saved state is locked. Files written before
+// the last boot are ignored.
+pub fn load_locked() -> Option<Instant> {
+    let path = path()?;
+    let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+    if boot_time().is_some_and(|boot| modified < boot) {
+        log::debug!("Ignoring lock state saved before the last boot");
+        return None;
+    }
+
+    let contents = fs::read_to_string(&path).ok()?;
+    let mut fields = contents.split_whitespace();
+    if fields.next()? != "locked" {
+        return None;
+    }
+
+    let since = fields
+        .next()
+        .and_then(|secs| secs.parse().ok())
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+        .and_then(|since| SystemTime::now().duration_since(since).ok())
+        .and_then(|elapsed| Instant::now().checked_sub(elapsed))
+        .unwrap_or_else(Instant::now);
+    Some(since)
+}
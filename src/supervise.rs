@@ -0,0 +1,48 @@
+use std::{fmt::Display, future::Future, time::Duration};
+use tokio::task::JoinSet;
+
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+const MAX_RESTARTS: u32 = 5;
+
+// Runs a backend on its own task and restarts it when it fails or panics, so that a crash
+// doesn't silently take its functionality down. Returning Ok means the backend has nothing
+// (left) to do and isn't restarted.
+pub async fn supervise<F, Fut, E>(backend: &str, mut serve: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+    E: Display + Send + 'static,
+{
+    let mut delay = INITIAL_DELAY;
+    let mut restarts = 0;
+    loop {
+        match tokio::spawn(serve()).await {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => log::error!("{backend} error: {e}"),
+            Err(e) if e.is_panic() => log::error!("{backend} panicked"),
+            Err(_) => return,
+        }
+
+        if restarts == MAX_RESTARTS {
+            log::error!("{backend} failed {MAX_RESTARTS} restarts, giving up");
+            return;
+        }
+        restarts += 1;
+
+        log::warn!("Restarting {backend} in {delay:?} (restart {restarts}/{MAX_RESTARTS})");
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_DELAY);
+    }
+}
+
+// Waits on the long-running tasks of a backend. Those never finish on their own, so the first
+// one to stop takes the rest down with it and fails the backend.
+pub async fn join(mut tasks: JoinSet<()>) -> anyhow::Result<()> {
+    match tasks.join_next().await {
+        Some(Ok(())) => Err(anyhow::anyhow!("a listener task stopped")),
+        Some(Err(e)) if e.is_panic() => Err(anyhow::anyhow!("a listener task panicked")),
+        Some(Err(e)) => Err(e.into()),
+        None => Ok(()),
+    }
+}
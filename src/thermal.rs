@@ -0,0 +1,44 @@
+use std::{fs, path::Path};
+
+const HWMON: &str = "/sys/class/hwmon";
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+// Temperature in °C of the first hwmon sensor whose chip name (e.g. "coretemp" or "k10temp")
+// or channel label (e.g. "Package id 0") equals `sensor`, None if there is no such sensor.
+pub fn temperature(sensor: &str) -> Option<f64> {
+    fs::read_dir(HWMON)
+        .ok()?
+        .flatten()
+        .find_map(|chip| chip_temperature(&chip.path(), sensor))
+}
+
+fn chip_temperature(chip: &Path, sensor: &str) -> Option<f64> {
+    let name_matches = read_trimmed(&chip.join("name")).is_some_and(|name| name == sensor);
+
+    let mut inputs = fs::read_dir(chip)
+        .ok()?
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|file| file.starts_with("temp") && file.ends_with("_input"))
+        .collect::<Vec<_>>();
+    inputs.sort();
+
+    inputs.into_iter().find_map(|input| {
+        let channel = input.trim_end_matches("_input");
+        if !name_matches
+            && read_trimmed(&chip.join(format!("{channel}_label")))
+                .is_none_or(|label| label != sensor)
+        {
+            return None;
+        }
+
+        // Reported in millidegrees Celsius.
+        let millidegrees = read_trimmed(&chip.join(&input))?.parse::<f64>().ok()?;
+        Some(millidegrees / 1000.0)
+    })
+}
@@ -0,0 +1,90 @@
+use crate::Moxidle;
+use std::collections::{HashMap, HashSet};
+use wayland_client::{
+    Connection, Dispatch, Proxy, QueueHandle, backend::ObjectId, event_created_child,
+};
+use wayland_protocols_wlr::foreign_toplevel::v1::client::{
+    zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
+    zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1},
+};
+
+#[derive(Default)]
+pub struct Toplevels {
+    // State changes are double buffered until the handle's done event.
+    pending: HashMap<ObjectId, bool>,
+    fullscreen: HashSet<ObjectId>,
+}
+
+impl Moxidle {
+    fn update_fullscreen_inhibitor(&mut self) {
+        let fullscreen = !self.toplevels.fullscreen.is_empty();
+        if fullscreen != self.inhibitors.fullscreen_inhibitor {
+            let action = if fullscreen { "Added" } else { "Removed" };
+            log::info!("{action} fullscreen inhibitor");
+
+            self.inhibitors.fullscreen_inhibitor = fullscreen;
+            self.reset_idle_timers();
+        }
+    }
+}
+
+impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for Moxidle {
+    fn event(
+        state: &mut Self,
+        _: &ZwlrForeignToplevelManagerV1,
+        event: zwlr_foreign_toplevel_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let zwlr_foreign_toplevel_manager_v1::Event::Finished = event {
+            state.toplevels.pending.clear();
+            state.toplevels.fullscreen.clear();
+            state.update_fullscreen_inhibitor();
+        }
+    }
+
+    event_created_child!(Moxidle, ZwlrForeignToplevelManagerV1, [
+        zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (ZwlrForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for Moxidle {
+    fn event(
+        state: &mut Self,
+        handle: &ZwlrForeignToplevelHandleV1,
+        event: zwlr_foreign_toplevel_handle_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_foreign_toplevel_handle_v1::Event::State { state: states } => {
+                let fullscreen = states
+                    .chunks_exact(4)
+                    .map(|chunk| u32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                    .any(|state| {
+                        state == zwlr_foreign_toplevel_handle_v1::State::Fullscreen as u32
+                    });
+                state.toplevels.pending.insert(handle.id(), fullscreen);
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::Done => {
+                if let Some(fullscreen) = state.toplevels.pending.remove(&handle.id()) {
+                    if fullscreen {
+                        state.toplevels.fullscreen.insert(handle.id());
+                    } else {
+                        state.toplevels.fullscreen.remove(&handle.id());
+                    }
+                    state.update_fullscreen_inhibitor();
+                }
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::Closed => {
+                state.toplevels.pending.remove(&handle.id());
+                state.toplevels.fullscreen.remove(&handle.id());
+                handle.destroy();
+                state.update_fullscreen_inhibitor();
+            }
+            _ => {}
+        }
+    }
+}
@@ -1,9 +1,13 @@
-use crate::Event;
+use crate::{Event, retry, supervise};
 use calloop::channel;
-use futures_lite::StreamExt;
+use futures_lite::{FutureExt, StreamExt};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 use std::{fmt::Display, sync::Arc};
-use zbus::{proxy, zvariant::OwnedValue};
+use tokio::task::JoinSet;
+use zbus::{
+    proxy,
+    zvariant::{OwnedObjectPath, OwnedValue},
+};
 
 #[derive(PartialEq, OwnedValue, Deserialize_repr, Serialize_repr, Default, Debug)]
 #[repr(u32)]
@@ -75,6 +79,9 @@ pub struct Power {
     level: BatteryLevel,
     state: BatteryState,
     percentage: f64,
+    percentage_known: bool,
+    time_to_empty: i64,
+    charger_online: bool,
 }
 
 #[derive(PartialEq)]
@@ -109,8 +116,29 @@ impl Power {
         };
     }
 
-    pub fn update_percentage(&mut self, new_percentage: f64) {
+    // Returns the previous percentage, or None if it wasn't known yet.
+    pub fn update_percentage(&mut self, new_percentage: f64) -> Option<f64> {
+        let previous = self.percentage_known.then_some(self.percentage);
         self.percentage = new_percentage.clamp(0.0, 100.0);
+        self.percentage_known = true;
+        previous
+    }
+
+    pub fn update_time_to_empty(&mut self, time_to_empty: i64) {
+        self.time_to_empty = time_to_empty;
+    }
+
+    // UPower reports 0 when the time to empty is unknown, e.g. while charging.
+    pub fn time_to_empty(&self) -> Option<i64> {
+        (self.time_to_empty > 0).then_some(self.time_to_empty)
+    }
+
+    pub fn update_charger_online(&mut self, online: bool) {
+        self.charger_online = online;
+    }
+
+    pub fn charger_online(&self) -> bool {
+        self.charger_online
     }
 
     pub fn level_cmp(&self, threshold: &f64) -> LevelComparison {
@@ -138,6 +166,14 @@ trait UPower {
 
     #[zbus(object = "Device")]
     fn get_display_device(&self);
+
+    fn enumerate_devices(&self) -> zbus::Result<Vec<OwnedObjectPath>>;
+
+    #[zbus(signal)]
+    fn device_added(&self, device: OwnedObjectPath) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn device_removed(&self, device: OwnedObjectPath) -> zbus::Result<()>;
 }
 
 #[proxy(
@@ -154,8 +190,20 @@ trait Device {
 
     #[zbus(property)]
     fn state(&self) -> zbus::Result<BatteryState>;
+
+    #[zbus(property)]
+    fn time_to_empty(&self) -> zbus::Result<i64>;
+
+    #[zbus(property, name = "Type")]
+    fn kind(&self) -> zbus::Result<u32>;
+
+    #[zbus(property)]
+    fn online(&self) -> zbus::Result<bool>;
 }
 
+// UPower's device type for AC adapters and docks.
+const LINE_POWER: u32 = 1;
+
 fn handle_battery_percentage(event_sender: &channel::Sender<Event>, value: f64) {
     if let Err(e) = event_sender.send(Event::BatteryPercentage(value)) {
         log::warn!("Failed to get BatteryPercentage args: {e}")
@@ -174,29 +222,188 @@ fn handle_battery_level(event_sender: &channel::Sender<Event>, value: BatteryLev
     }
 }
 
+fn handle_time_to_empty(event_sender: &channel::Sender<Event>, value: i64) {
+    if let Err(e) = event_sender.send(Event::BatteryTimeToEmpty(value)) {
+        log::warn!("Failed to send BatteryTimeToEmpty event: {e}")
+    }
+}
+
+fn handle_charger_online(event_sender: &channel::Sender<Event>, value: bool) {
+    if let Err(e) = event_sender.send(Event::ChargerOnline(value)) {
+        log::warn!("Failed to send ChargerOnline event: {e}")
+    }
+}
+
 fn handle_on_battery(event_sender: &channel::Sender<Event>, value: bool) {
     if let Err(e) = event_sender.send(Event::OnBattery(value)) {
         log::warn!("Failed to send OnBattery event: {e}")
     }
 }
 
+// Uses the device at `battery_device` when set, otherwise the aggregated display device.
+async fn device_proxy<'a>(
+    upower: &UPowerProxy<'a>,
+    battery_device: Option<&'a str>,
+) -> zbus::Result<DeviceProxy<'a>> {
+    match battery_device {
+        Some(path) => {
+            DeviceProxy::builder(upower.inner().connection())
+                .path(path)?
+                .build()
+                .await
+        }
+        None => upower.get_display_device().await,
+    }
+}
+
+const UPOWER_SERVICE: &str = "org.freedesktop.UPower";
+
+// Whether UPower is running or can be activated, the proxies only fail once they are used.
+async fn is_available(connection: &zbus::Connection) -> zbus::Result<bool> {
+    let dbus = zbus::fdo::DBusProxy::new(connection).await?;
+    if dbus
+        .name_has_owner(zbus::names::BusName::try_from(UPOWER_SERVICE)?)
+        .await?
+    {
+        return Ok(true);
+    }
+
+    Ok(dbus
+        .list_activatable_names()
+        .await?
+        .iter()
+        .any(|name| name.as_str() == UPOWER_SERVICE))
+}
+
+// Every line-power device UPower knows about, a dock may report separately from the adapter.
+async fn line_power_devices(upower: &UPowerProxy<'_>) -> zbus::Result<Vec<DeviceProxy<'static>>> {
+    let mut devices = Vec::new();
+    for path in upower.enumerate_devices().await? {
+        let device = DeviceProxy::builder(upower.inner().connection())
+            .path(path)?
+            .build()
+            .await?;
+        if device.kind().await? == LINE_POWER {
+            devices.push(device);
+        }
+    }
+
+    Ok(devices)
+}
+
+// The charger counts as online while any line-power device is.
+async fn any_online(devices: &[DeviceProxy<'static>]) -> bool {
+    for device in devices {
+        if device.online().await.unwrap_or(false) {
+            return true;
+        }
+    }
+
+    false
+}
+
+// Watches the line-power devices and enumerates them again whenever UPower adds or removes a
+// device, e.g. when a dock is plugged in.
+async fn watch_chargers(upower: UPowerProxy<'static>, event_sender: channel::Sender<Event>) {
+    let (mut added_stream, mut removed_stream) = match (
+        upower.receive_device_added().await,
+        upower.receive_device_removed().await,
+    ) {
+        (Ok(added), Ok(removed)) => (added, removed),
+        (Err(e), _) | (_, Err(e)) => {
+            log::error!("Failed to subscribe to UPower devices: {e}");
+            return;
+        }
+    };
+    log::info!("ChargerOnline listener active");
+
+    loop {
+        // Dropping the set stops watching the devices of the previous enumeration.
+        let mut watchers = JoinSet::new();
+        match line_power_devices(&upower).await {
+            Ok(devices) => {
+                if devices.is_empty() {
+                    log::info!("No line-power device found, charger_online isn't met until one is");
+                }
+                let devices = Arc::new(devices);
+                for device in devices.iter() {
+                    let mut online_stream = device.receive_online_changed().await;
+                    let devices = Arc::clone(&devices);
+                    let event_sender = event_sender.clone();
+                    watchers.spawn(async move {
+                        while online_stream.next().await.is_some() {
+                            handle_charger_online(&event_sender, any_online(&devices).await);
+                        }
+                    });
+                }
+                handle_charger_online(&event_sender, any_online(&devices).await);
+            }
+            Err(e) => log::error!("Failed to enumerate line-power devices: {e}"),
+        }
+
+        let changed = async { added_stream.next().await.map(drop) }
+            .or(async { removed_stream.next().await.map(drop) })
+            .await;
+        if changed.is_none() {
+            return;
+        }
+    }
+}
+
+async fn properties_proxy(
+    device: &DeviceProxy<'_>,
+) -> zbus::Result<zbus::fdo::PropertiesProxy<'static>> {
+    zbus::fdo::PropertiesProxy::builder(device.inner().connection())
+        .destination(device.inner().destination().to_owned())?
+        .path(device.inner().path().to_owned())?
+        .build()
+        .await
+}
+
+// Properties no condition or command needs, they aren't watched.
+#[derive(Clone, Copy)]
+pub struct Ignored {
+    pub on_battery: bool,
+    pub battery_percentage: bool,
+    pub battery_state: bool,
+    pub battery_level: bool,
+    pub time_to_empty: bool,
+    pub charger_online: bool,
+}
+
 pub async fn serve(
     connection: Arc<zbus::Connection>,
     event_sender: channel::Sender<Event>,
-    ignore_on_battery: bool,
-    ignore_battery_percentage: bool,
-    ignore_battery_state: bool,
-    ignore_battery_level: bool,
-) -> zbus::Result<()> {
+    ignored: Ignored,
+    battery_device: Option<Arc<str>>,
+) -> anyhow::Result<()> {
+    let Ignored {
+        on_battery: ignore_on_battery,
+        battery_percentage: ignore_battery_percentage,
+        battery_state: ignore_battery_state,
+        battery_level: ignore_battery_level,
+        time_to_empty: ignore_time_to_empty,
+        charger_online: ignore_charger_online,
+    } = ignored;
     if ignore_on_battery
         && ignore_battery_percentage
         && ignore_battery_state
         && ignore_battery_level
+        && ignore_time_to_empty
+        && ignore_charger_online
     {
         return Ok(());
     }
 
-    let upower = UPowerProxy::new(&connection).await?;
+    if !is_available(&connection).await? {
+        if let Err(e) = event_sender.send(Event::UPowerUnavailable) {
+            log::error!("Failed to send UPowerUnavailable event: {e}");
+        }
+        return Ok(());
+    }
+
+    let upower = retry::with_backoff("connect to UPower", || UPowerProxy::new(&connection)).await?;
+    let mut tasks = JoinSet::new();
 
     if !ignore_on_battery {
         let mut on_battery_stream = upower.receive_on_battery_changed().await;
@@ -206,7 +413,7 @@ pub async fn serve(
             handle_on_battery(&event_sender, on_battery);
         }
 
-        tokio::spawn(async move {
+        tasks.spawn(async move {
             while let Some(event) = on_battery_stream.next().await {
                 if let Ok(on_battery) = event.get().await {
                     handle_on_battery(&event_sender, on_battery);
@@ -215,71 +422,106 @@ pub async fn serve(
         });
     }
 
-    if ignore_battery_percentage && ignore_battery_state && ignore_battery_level {
-        return Ok(());
+    if !ignore_charger_online {
+        tasks.spawn(watch_chargers(upower.clone(), event_sender.clone()));
     }
 
-    let upower_clone = upower.clone();
-    let event_sender_clone = event_sender.clone();
-    tokio::spawn(async move {
-        let device = match upower_clone.get_display_device().await {
+    if ignore_battery_percentage
+        && ignore_battery_state
+        && ignore_battery_level
+        && ignore_time_to_empty
+    {
+        return supervise::join(tasks).await;
+    }
+
+    tasks.spawn(async move {
+        let device = match device_proxy(&upower, battery_device.as_deref()).await {
             Ok(device) => device,
             Err(e) => {
-                log::error!("Failed to get display device: {e}");
+                log::error!("Failed to get battery device: {e}");
                 return;
             }
         };
 
+        let mut properties_stream = match properties_proxy(&device).await {
+            Ok(properties) => match properties.receive_properties_changed().await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::error!("Failed to subscribe to battery device properties: {e}");
+                    return;
+                }
+            },
+            Err(e) => {
+                log::error!("Failed to create battery device properties proxy: {e}");
+                return;
+            }
+        };
+
+        // Initial values are read after subscribing so that no change can slip in between.
         if !ignore_battery_percentage {
-            let mut percentage_stream = device.receive_percentage_changed().await;
             log::info!("BatteryPercentage listener active");
-
-            let event_sender = event_sender_clone.clone();
-            tokio::spawn(async move {
-                while let Some(event) = percentage_stream.next().await {
-                    if let Ok(percentage) = event.get().await {
-                        handle_battery_percentage(&event_sender, percentage);
-                    }
-                }
-            });
+            if let Ok(percentage) = device.percentage().await {
+                handle_battery_percentage(&event_sender, percentage);
+            }
         }
 
         if !ignore_battery_state {
+            log::info!("BatteryState listener active");
             if let Ok(state) = device.state().await {
-                handle_state(&event_sender_clone, state);
+                handle_state(&event_sender, state);
             }
-
-            let mut state_stream = device.receive_state_changed().await;
-            log::info!("BatteryState listener active");
-
-            let event_sender = event_sender_clone.clone();
-            tokio::spawn(async move {
-                while let Some(event) = state_stream.next().await {
-                    if let Ok(state) = event.get().await {
-                        handle_state(&event_sender, state);
-                    }
-                }
-            });
         }
 
         if !ignore_battery_level {
+            log::info!("BatteryLevel listener active");
             if let Ok(level) = device.battery_level().await {
-                handle_battery_level(&event_sender_clone, level);
+                handle_battery_level(&event_sender, level);
             }
+        }
 
-            let mut level_stream = device.receive_battery_level_changed().await;
-            log::info!("BatteryLevel listener active");
+        if !ignore_time_to_empty {
+            log::info!("TimeToEmpty listener active");
+            if let Ok(time_to_empty) = device.time_to_empty().await {
+                handle_time_to_empty(&event_sender, time_to_empty);
+            }
+        }
+
+        while let Some(signal) = properties_stream.next().await {
+            let Ok(args) = signal.args() else {
+                continue;
+            };
+
+            if args.interface_name.as_str() != "org.freedesktop.UPower.Device" {
+                continue;
+            }
 
-            let event_sender = event_sender_clone.clone();
-            tokio::spawn(async move {
-                while let Some(event) = level_stream.next().await {
-                    if let Ok(level) = event.get().await {
-                        handle_battery_level(&event_sender, level);
-                    }
+            args.changed_properties.iter().for_each(|(&name, value)| {
+                let result = match name {
+                    "Percentage" if !ignore_battery_percentage => value
+                        .try_to_owned()
+                        .and_then(f64::try_from)
+                        .map(|percentage| handle_battery_percentage(&event_sender, percentage)),
+                    "State" if !ignore_battery_state => value
+                        .try_to_owned()
+                        .and_then(BatteryState::try_from)
+                        .map(|state| handle_state(&event_sender, state)),
+                    "BatteryLevel" if !ignore_battery_level => value
+                        .try_to_owned()
+                        .and_then(BatteryLevel::try_from)
+                        .map(|level| handle_battery_level(&event_sender, level)),
+                    "TimeToEmpty" if !ignore_time_to_empty => value
+                        .try_to_owned()
+                        .and_then(i64::try_from)
+                        .map(|time_to_empty| handle_time_to_empty(&event_sender, time_to_empty)),
+                    _ => Ok(()),
+                };
+
+                if let Err(e) = result {
+                    log::warn!("Failed to parse {name} property: {e}");
                 }
             });
         }
     });
 
-    Ok(())
+    supervise::join(tasks).await
 }
@@ -1,53 +1,302 @@
 use crate::Event;
 use calloop::channel;
-use rusb::{Device, Interfaces, UsbContext};
+use rusb::{ConfigDescriptor, Device, DeviceDescriptor, Interfaces, UsbContext};
 use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, os::fd::RawFd, sync::Arc};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceEvent {
-    name: String,
-    event: String,
+    pub name: String,
+    pub event: String,
+    pub vendor: u16,
+    pub product: u16,
+    // Class codes of the device and its interfaces, only known for added devices.
+    #[serde(skip)]
+    pub classes: Vec<u8>,
+}
+
+impl std::fmt::Display for DeviceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {:04x}:{:04x}",
+            self.event, self.name, self.vendor, self.product
+        )
+    }
+}
+
+// Vendor and product ids of connected devices with their classes, kept up to date by hotplug
+// events so that conditions don't have to enumerate devices. Counted since identical devices
+// share ids.
+#[derive(Default)]
+pub struct DeviceCache {
+    ids: HashMap<(u16, u16), (usize, Vec<u8>)>,
+}
+
+impl DeviceCache {
+    pub fn update(&mut self, device_event: &DeviceEvent) {
+        let id = (device_event.vendor, device_event.product);
+        match device_event.event.as_str() {
+            "Added" => {
+                let (count, _) = self
+                    .ids
+                    .entry(id)
+                    .or_insert_with(|| (0, device_event.classes.clone()));
+                *count += 1;
+            }
+            "Removed" => {
+                if let Some((count, _)) = self.ids.get_mut(&id) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.ids.remove(&id);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.ids.values().map(|(count, _)| count).sum()
+    }
+
+    fn any<F>(&self, mut predicate: F) -> bool
+    where
+        F: FnMut(u16, u16, &[u8]) -> bool,
+    {
+        self.ids
+            .iter()
+            .any(|(&(vendor, product), (_, classes))| predicate(vendor, product, classes))
+    }
+}
+
+// Whether a device matching `matcher` is connected, or None if devices can't be enumerated.
+// The cache is used unless the matcher filters on the serial number.
+pub fn is_plugged(
+    matcher: &UsbMatcher,
+    cache: Option<&DeviceCache>,
+    usb_context: Option<&rusb::Context>,
+) -> Option<bool> {
+    if let Some(cache) = cache
+        && matcher.serial.is_none()
+    {
+        return Some(cache.any(|vendor, product, classes| {
+            matcher.matches_id(vendor, product)
+                && matcher.class.is_none_or(|class| classes.contains(&class))
+        }));
+    }
+
+    usb_context
+        .and_then(|ctx| ctx.devices().ok())
+        .map(|devices| devices.iter().any(|device| matcher.matches(&device)))
+}
+
+// Number of connected devices, hubs included, or None if devices can't be enumerated.
+pub fn device_count(
+    cache: Option<&DeviceCache>,
+    usb_context: Option<&rusb::Context>,
+) -> Option<usize> {
+    if let Some(cache) = cache {
+        return Some(cache.count());
+    }
+
+    usb_context
+        .and_then(|ctx| ctx.devices().ok())
+        .map(|devices| devices.len())
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct UsbMatcher {
+    // None matches any device.
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+    pub serial: Option<Arc<str>>,
+    pub class: Option<u8>,
+}
+
+impl UsbMatcher {
+    fn matches_id(&self, vendor: u16, product: u16) -> bool {
+        self.vendor.is_none_or(|v| v == vendor) && self.product.is_none_or(|p| p == product)
+    }
+
+    pub fn matches<T: UsbContext>(&self, device: &Device<T>) -> bool {
+        let Ok(desc) = device.device_descriptor() else {
+            return false;
+        };
+
+        if !self.matches_id(desc.vendor_id(), desc.product_id()) {
+            return false;
+        }
+
+        if let Some(class) = self.class
+            && !has_class(device, &desc, class)
+        {
+            return false;
+        }
+
+        let Some(serial) = self.serial.as_ref() else {
+            return true;
+        };
+
+        match device
+            .open()
+            .and_then(|handle| handle.read_serial_number_string_ascii(&desc))
+        {
+            Ok(device_serial) => device_serial == **serial,
+            Err(rusb::Error::Access) => {
+                log::warn!(
+                    "No permission to read serial number of USB device {:04x}:{:04x}, ignoring serial filter",
+                    desc.vendor_id(),
+                    desc.product_id()
+                );
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+fn has_class<T: UsbContext>(device: &Device<T>, desc: &DeviceDescriptor, class: u8) -> bool {
+    classes(device, desc).contains(&class)
+}
+
+// The device class followed by the classes of its interfaces.
+fn classes<T: UsbContext>(device: &Device<T>, desc: &DeviceDescriptor) -> Vec<u8> {
+    let mut classes = vec![desc.class_code()];
+    if let Ok(config) = device.active_config_descriptor() {
+        for interface in config.interfaces() {
+            classes.extend(
+                interface
+                    .descriptors()
+                    .map(|descriptor| descriptor.class_code()),
+            );
+        }
+    }
+    classes
 }
 
 struct HotPlugHandler(Box<dyn FnMut(DeviceEvent) + 'static + Send>);
 
 impl<T: UsbContext> rusb::Hotplug<T> for HotPlugHandler {
     fn device_arrived(&mut self, device: Device<T>) {
+        let Ok(desc) = device_descriptor(&device) else {
+            return;
+        };
+        let (vendor, product) = (desc.vendor_id(), desc.product_id());
         (self.0)(DeviceEvent {
-            name: get_class_name(device.active_config_descriptor().unwrap().interfaces()),
+            name: device_name(device.active_config_descriptor(), vendor, product),
             event: "Added".to_string(),
+            vendor,
+            product,
+            classes: classes(&device, &desc),
         });
     }
 
     fn device_left(&mut self, device: Device<T>) {
+        let Ok(desc) = device_descriptor(&device) else {
+            return;
+        };
+        let (vendor, product) = (desc.vendor_id(), desc.product_id());
         (self.0)(DeviceEvent {
-            name: get_class_name(device.config_descriptor(0).unwrap().interfaces()),
+            name: device_name(device.config_descriptor(0), vendor, product),
             event: "Removed".to_string(),
+            vendor,
+            product,
+            classes: Vec::new(),
         });
     }
 }
 
+// libusb caches device descriptors, so they stay readable after the device has left.
+fn device_descriptor<T: UsbContext>(device: &Device<T>) -> rusb::Result<DeviceDescriptor> {
+    device.device_descriptor().inspect_err(|e| {
+        log::warn!(
+            "Skipping USB device on bus {} address {}, failed to read its descriptor: {e}",
+            device.bus_number(),
+            device.address()
+        );
+    })
+}
+
+// Flaky devices and hubs can fail descriptor reads, they are reported as Unknown.
+fn device_name(config: rusb::Result<ConfigDescriptor>, vendor: u16, product: u16) -> String {
+    match config {
+        Ok(config) => get_class_name(config.interfaces()),
+        Err(e) => {
+            log::warn!(
+                "Failed to read config descriptor of USB device {vendor:04x}:{product:04x}: {e}"
+            );
+            "Unknown".to_string()
+        }
+    }
+}
+
+fn class_name(class_code: u8) -> &'static str {
+    match class_code {
+        1 => "Audio",
+        2 => "COMM",
+        3 => "HID",
+        5 => "Physical",
+        6 => "PTP",
+        7 => "Printer",
+        8 => "MassStorage",
+        9 => "Hub",
+        10 => "Data",
+        _ => "Unknown",
+    }
+}
+
+// Class code for one of the names above.
+pub fn class_code(name: &str) -> Option<u8> {
+    (0..=u8::MAX).find(|&class_code| name != "Unknown" && class_name(class_code) == name)
+}
+
 fn get_class_name(interfaces: Interfaces) -> String {
-    let mut class_name = String::new();
+    let mut name = String::new();
 
     for interface in interfaces {
         for descriptor in interface.descriptors() {
-            class_name = match descriptor.class_code() {
-                1 => "Audio",
-                2 => "COMM",
-                3 => "HID",
-                5 => "Physical",
-                6 => "PTP",
-                7 => "Printer",
-                8 => "MassStorage",
-                9 => "Hub",
-                10 => "Data",
-                _ => "Unknown",
-            }
-            .to_string();
+            name = class_name(descriptor.class_code()).to_string();
+        }
+    }
+    name
+}
+
+pub struct PollFd {
+    pub fd: RawFd,
+    pub readable: bool,
+    pub writable: bool,
+}
+
+// Returns the file descriptors libusb wants polled, or None if the platform can't express
+// all of its pending work through them and handle_events has to be called periodically instead.
+pub fn pollfds(usb_context: &rusb::Context) -> Option<Vec<PollFd>> {
+    // SAFETY: The context outlives these calls and the list is only read before being freed.
+    unsafe {
+        if rusb::ffi::libusb_pollfds_handle_timeouts(usb_context.as_raw()) == 0 {
+            return None;
+        }
+
+        let list = rusb::ffi::libusb_get_pollfds(usb_context.as_raw());
+        if list.is_null() {
+            return None;
+        }
+
+        let mut pollfds = Vec::new();
+        let mut entry = list;
+        while !(*entry).is_null() {
+            let pollfd = &**entry;
+            pollfds.push(PollFd {
+                fd: pollfd.fd,
+                readable: pollfd.events & libc::POLLIN != 0,
+                writable: pollfd.events & libc::POLLOUT != 0,
+            });
+            entry = entry.add(1);
         }
+        rusb::ffi::libusb_free_pollfds(list);
+
+        Some(pollfds)
     }
-    class_name
 }
 
 pub fn serve(
@@ -56,12 +305,12 @@ pub fn serve(
 ) -> anyhow::Result<()> {
     let registration = rusb::HotplugBuilder::new().enumerate(true).register(
         usb_context,
-        Box::new(HotPlugHandler(Box::new(move |_| {
-            if let Err(e) = event_sender.send(Event::Usb) {
+        Box::new(HotPlugHandler(Box::new(move |device_event| {
+            if let Err(e) = event_sender.send(Event::Usb(device_event)) {
                 log::error!("{e}");
             }
         }))),
-    );
+    )?;
 
     Box::leak(Box::new(registration));
 